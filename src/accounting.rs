@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use tracing::{error, info};
+
+use crate::capture::Direction;
+use crate::rate_limit::parse_packets_bytes;
+
+/// Period accumulating the whole traffic of a client, besides the daily and monthly ones
+const TOTAL: &str = "total";
+
+/// Packets and bytes a client can exchange with the server in a period, zero meaning unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (packets, bytes) = parse_packets_bytes(s, "quota")?;
+        Ok(Quota { packets, bytes })
+    }
+}
+
+impl Quota {
+    fn is_exceeded_by(&self, counters: &Counters) -> bool {
+        (self.packets > 0 && counters.packets() >= self.packets)
+            || (self.bytes > 0 && counters.bytes() >= self.bytes)
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.packets == 0 && self.bytes == 0
+    }
+}
+
+/// What happens to the clients which exceeded their quotas
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaAction {
+    Reject,
+    Throttle,
+}
+
+impl FromStr for QuotaAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(QuotaAction::Reject),
+            "throttle" => Ok(QuotaAction::Throttle),
+            _ => Err(format!("Unknown quota action {}", s)),
+        }
+    }
+}
+
+/// Traffic of a client in a period, up being from the client to the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub up_packets: u64,
+    pub up_bytes: u64,
+    pub down_packets: u64,
+    pub down_bytes: u64,
+}
+
+impl Counters {
+    pub fn packets(&self) -> u64 {
+        self.up_packets + self.down_packets
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.up_bytes + self.down_bytes
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "up {} packets/{} bytes, down {} packets/{} bytes",
+            self.up_packets, self.up_bytes, self.down_packets, self.down_bytes
+        )
+    }
+}
+
+/// Traffic of each client identity by day, month and in total, which outlives the client slots
+/// and is persisted in a tab separated text file, when given, to be reloaded on restart
+pub struct Accounting {
+    path: Option<String>,
+    daily: Quota,
+    monthly: Quota,
+    usage: BTreeMap<(String, String), Counters>,
+}
+
+impl Accounting {
+    pub fn new(path: Option<String>, daily: Quota, monthly: Quota) -> Self {
+        let usage = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| parse(&content))
+            .unwrap_or_default();
+        if let Some(path) = &path {
+            info!("Loaded {} accounting records from {}", usage.len(), path);
+        }
+        Accounting {
+            path,
+            daily,
+            monthly,
+            usage,
+        }
+    }
+
+    /// Whether traffic is persisted or subject to quotas, otherwise it is not worth logging
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some() || !self.daily.is_unlimited() || !self.monthly.is_unlimited()
+    }
+
+    pub fn record(&mut self, identity: &str, direction: Direction, length: usize) {
+        self.record_at(identity, direction, length, Utc::now())
+    }
+
+    fn record_at(
+        &mut self,
+        identity: &str,
+        direction: Direction,
+        length: usize,
+        now: DateTime<Utc>,
+    ) {
+        periods(now).into_iter().for_each(|period| {
+            let counters = self.usage.entry((identity.to_owned(), period)).or_default();
+            match direction {
+                Direction::Inbound => {
+                    counters.up_packets += 1;
+                    counters.up_bytes += length as u64;
+                }
+                Direction::Outbound => {
+                    counters.down_packets += 1;
+                    counters.down_bytes += length as u64;
+                }
+            }
+        });
+    }
+
+    /// Traffic of the client today
+    pub fn today(&self, identity: &str) -> Counters {
+        let [day, _, _] = periods(Utc::now());
+        self.usage(identity, &day)
+    }
+
+    pub fn is_exceeded(&self, identity: &str) -> bool {
+        self.is_exceeded_at(identity, Utc::now())
+    }
+
+    fn is_exceeded_at(&self, identity: &str, now: DateTime<Utc>) -> bool {
+        let [day, month, _] = periods(now);
+        self.daily.is_exceeded_by(&self.usage(identity, &day))
+            || self.monthly.is_exceeded_by(&self.usage(identity, &month))
+    }
+
+    fn usage(&self, identity: &str, period: &str) -> Counters {
+        self.usage
+            .get(&(identity.to_owned(), period.to_owned()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Drops the daily records older than the current month, which no quota looks at anymore,
+    /// keeping the monthly and total ones
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let [_, month, _] = periods(now);
+        self.usage.retain(|(_, period), _| {
+            period.matches('-').count() != 2 || period.as_str() >= month.as_str()
+        });
+    }
+
+    /// Writes the records to a temporary file replacing the accounting file, not to leave it
+    /// truncated if interrupted. The daily records of the past months are dropped beforehand.
+    pub fn save(&mut self) {
+        self.prune(Utc::now());
+        if let Some(path) = &self.path {
+            let temporary = format!("{}.tmp", path);
+            if let Err(e) = fs::write(&temporary, format(&self.usage))
+                .and_then(|_| fs::rename(&temporary, path))
+            {
+                error!("Can't save accounting to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Day, month and total periods the traffic at the given instant counts towards
+fn periods(now: DateTime<Utc>) -> [String; 3] {
+    [
+        now.format("%Y-%m-%d").to_string(),
+        now.format("%Y-%m").to_string(),
+        String::from(TOTAL),
+    ]
+}
+
+fn format(usage: &BTreeMap<(String, String), Counters>) -> String {
+    let mut content =
+        String::from("# identity\tperiod\tup_packets\tup_bytes\tdown_packets\tdown_bytes\n");
+    usage.iter().for_each(|((identity, period), counters)| {
+        content.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            identity,
+            period,
+            counters.up_packets,
+            counters.up_bytes,
+            counters.down_packets,
+            counters.down_bytes
+        ))
+    });
+    content
+}
+
+fn parse(content: &str) -> BTreeMap<(String, String), Counters> {
+    content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            let numbers = fields
+                .iter()
+                .skip(2)
+                .map(|field| field.parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>();
+            match (fields.as_slice(), numbers.as_deref()) {
+                ([identity, period, ..], Ok([up_packets, up_bytes, down_packets, down_bytes])) => {
+                    Some((
+                        (identity.to_string(), period.to_string()),
+                        Counters {
+                            up_packets: *up_packets,
+                            up_bytes: *up_bytes,
+                            down_packets: *down_packets,
+                            down_bytes: *down_bytes,
+                        },
+                    ))
+                }
+                _ => {
+                    error!("Ignoring malformed accounting record: {}", line);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_quotas() {
+        let mut accounting = Accounting::new(None, "3:".parse().unwrap(), ":1000".parse().unwrap());
+        let first_day = Utc.with_ymd_and_hms(2026, 10, 19, 23, 0, 0).unwrap();
+        let next_day = Utc.with_ymd_and_hms(2026, 10, 20, 1, 0, 0).unwrap();
+        accounting.record_at("team", Direction::Inbound, 100, first_day);
+        accounting.record_at("team", Direction::Outbound, 100, first_day);
+        assert!(!accounting.is_exceeded_at("team", first_day));
+        accounting.record_at("team", Direction::Inbound, 100, first_day);
+        assert!(accounting.is_exceeded_at("team", first_day));
+        assert!(!accounting.is_exceeded_at("other", first_day));
+
+        // The daily quota resets, the monthly one keeps counting
+        assert!(!accounting.is_exceeded_at("team", next_day));
+        accounting.record_at("team", Direction::Inbound, 700, next_day);
+        assert!(accounting.is_exceeded_at("team", next_day));
+        assert_eq!(accounting.usage("team", TOTAL).bytes(), 1000);
+
+        // Only the daily records of the past months are dropped
+        let next_month = Utc.with_ymd_and_hms(2026, 11, 1, 0, 0, 0).unwrap();
+        accounting.record_at("team", Direction::Inbound, 100, next_month);
+        accounting.prune(next_month);
+        let periods: Vec<&str> = accounting.usage.keys().map(|(_, p)| p.as_str()).collect();
+        assert_eq!(periods, vec!["2026-10", "2026-11", "2026-11-01", TOTAL]);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("accounting-{}.tsv", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let mut accounting =
+            Accounting::new(Some(path.clone()), Quota::default(), Quota::default());
+        accounting.record("team", Direction::Inbound, 10);
+        accounting.record("team", Direction::Outbound, 20);
+        accounting.save();
+
+        let reloaded = Accounting::new(Some(path.clone()), Quota::default(), Quota::default());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.usage, accounting.usage);
+        assert_eq!(
+            reloaded.today("team"),
+            Counters {
+                up_packets: 1,
+                up_bytes: 10,
+                down_packets: 1,
+                down_bytes: 20
+            }
+        );
+        assert!(parse("team\t2026-10\t1\t2\n").is_empty());
+    }
+}
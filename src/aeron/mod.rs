@@ -4,14 +4,21 @@ use aeron_rs::example_config::DEFAULT_STREAM_ID;
 
 use crate::aeron::publisher::Publisher;
 use crate::aeron::subscriber::Subscriber;
-use crate::Arguments;
+use crate::{Arguments, Timeout};
+use aeron_rs::concurrent::counters::CountersReader;
+use aeron_rs::concurrent::status::status_indicator_reader::{
+    channel_status_to_str, CHANNEL_ENDPOINT_ACTIVE, CHANNEL_ENDPOINT_ERRORED,
+    CHANNEL_ENDPOINT_INITIALIZING,
+};
 use aeron_rs::context::Context;
 use aeron_rs::publication::Publication;
 use aeron_rs::subscription::Subscription;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub(crate) mod publisher;
 pub(crate) mod subscriber;
+pub(crate) mod transport;
 
 #[derive(Clone)]
 pub struct Settings {
@@ -24,6 +31,10 @@ pub struct Settings {
     pub message_length: i32,
     #[allow(dead_code)]
     linger_timeout_ms: u64,
+    pub handshake_retry: Duration,
+    pub handshake_timeout: Duration,
+    pub connection_timeout: Duration,
+    pub session_timeout: Duration,
 }
 
 impl Settings {
@@ -35,6 +46,10 @@ impl Settings {
             number_of_messages: 10,
             message_length: args.mtu as i32,
             linger_timeout_ms: 100,
+            handshake_retry: Duration::from_secs(Timeout::HANDSHAKE_RETRY_SECONDS),
+            handshake_timeout: Duration::from_secs(Timeout::HANDSHAKE_SECONDS),
+            connection_timeout: Duration::from_secs(Timeout::CONNECTION_SECONDS),
+            session_timeout: Duration::from_secs(Timeout::SESSION_SECONDS),
         }
     }
 }
@@ -43,24 +58,67 @@ pub fn str_to_c(val: &str) -> CString {
     CString::new(val).expect("Error converting str to CString")
 }
 
+/// Longest wait for the driver to open the channel of a new publication or subscription
+const CHANNEL_ACTIVATION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Waits for the driver to try to bind the channel of a publication or subscription, failing
+/// when it couldn't, such as when its port is already bound. The status is read from the
+/// counters, since the client conductor locks the publication or subscription on errors.
+pub fn await_channel<F>(
+    counters: &CountersReader,
+    channel_status_id: i32,
+    is_closed: F,
+) -> Result<(), String>
+where
+    F: Fn() -> bool,
+{
+    let channel_status = || {
+        if is_closed() {
+            CHANNEL_ENDPOINT_ERRORED
+        } else {
+            counters
+                .counter_value(channel_status_id)
+                .map(|status| status as i64)
+                .unwrap_or(CHANNEL_ENDPOINT_ACTIVE)
+        }
+    };
+    let started = Instant::now();
+    while channel_status() == CHANNEL_ENDPOINT_INITIALIZING
+        && started.elapsed() < CHANNEL_ACTIVATION_TIMEOUT
+    {
+        std::thread::yield_now();
+    }
+    if channel_status() == CHANNEL_ENDPOINT_ERRORED {
+        return Err(format!(
+            "Channel status {}",
+            channel_status_to_str(CHANNEL_ENDPOINT_ERRORED)
+        ));
+    }
+    Ok(())
+}
+
 pub fn instance_publisher(
     context: Context,
     settings: &Settings,
     channel: &str,
-) -> (Publisher, Arc<Mutex<Publication>>) {
+) -> Result<(Publisher, Arc<Mutex<Publication>>), String> {
     let publisher = Publisher::new(context, settings, channel)
         .unwrap_or_else(|_| panic!("Error creating publisher on channel {}", channel));
-    let publication = publisher.publish();
-    (publisher, publication)
+    let publication = publisher
+        .publish()
+        .map_err(|e| format!("Error creating publication on channel {}: {}", channel, e))?;
+    Ok((publisher, publication))
 }
 
 pub fn instance_subscriber(
     context: Context,
     settings: &Settings,
     channel: &str,
-) -> (Subscriber, Arc<Mutex<Subscription>>) {
+) -> Result<(Subscriber, Arc<Mutex<Subscription>>), String> {
     let subscriber = Subscriber::new(context, settings, channel)
         .unwrap_or_else(|_| panic!("Error creating subscriber on channel {}", channel));
-    let subscription = subscriber.listen();
-    (subscriber, subscription)
+    let subscription = subscriber
+        .listen()
+        .map_err(|e| format!("Error creating subscription on channel {}: {}", channel, e))?;
+    Ok((subscriber, subscription))
 }
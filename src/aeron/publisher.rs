@@ -4,13 +4,15 @@ use std::sync::{Arc, Mutex};
 
 use aeron_rs::concurrent::atomic_buffer::{AlignedBuffer, AtomicBuffer};
 use aeron_rs::publication::Publication;
+use aeron_rs::utils::types::Index;
 use aeron_rs::{
     aeron::Aeron, concurrent::status::status_indicator_reader::channel_status_to_str,
     context::Context, utils::errors::AeronError,
 };
-use log::{debug, error, info};
+use tracing::{debug, error, info};
 
-use crate::aeron::{str_to_c, Settings};
+use crate::aeron::{await_channel, str_to_c, Settings};
+use crate::logging::Throttle;
 use std::time::Duration;
 
 pub fn error_handler(error: AeronError) {
@@ -36,6 +38,8 @@ pub struct Publisher {
     aeron: RefCell<Aeron>,
     settings: Settings,
     channel: String,
+    send_errors: Throttle,
+    unsubscribed: Throttle,
 }
 
 impl Publisher {
@@ -69,13 +73,26 @@ impl Publisher {
             aeron: RefCell::new(aeron.unwrap()),
             settings: settings.clone(),
             channel: channel.to_owned(),
+            send_errors: Throttle::default(),
+            unsubscribed: Throttle::default(),
         })
     }
 
-    pub fn publish(&self) -> Arc<Mutex<Publication>> {
+    /// Adds the publication, failing when the driver can't open its channel, such as when its
+    /// control port is already bound
+    pub fn publish(&self) -> Result<Arc<Mutex<Publication>>, String> {
         let publication = self
             .create_pubblication()
             .expect("Error creating publication");
+        let counters = self
+            .aeron
+            .borrow()
+            .counters_reader()
+            .map_err(|e| format!("{:?}", e))?;
+        let channel_status_id = publication.lock().unwrap().channel_status_id();
+        await_channel(&counters, channel_status_id, || {
+            publication.lock().unwrap().is_closed()
+        })?;
 
         if publication.lock().is_err() {
             let channel_status = publication
@@ -90,11 +107,12 @@ impl Publisher {
             );
         }
 
-        publication
+        Ok(publication)
     }
 
     pub fn send(&self, publication: Arc<Mutex<Publication>>, buffer: &[u8], buffer_size: usize) {
-        let aligned_buffer = AlignedBuffer::with_capacity(self.settings.message_length);
+        let aligned_buffer =
+            AlignedBuffer::with_capacity(self.settings.message_length.max(buffer.len() as Index));
         let src_buffer = AtomicBuffer::from_aligned(&aligned_buffer);
         src_buffer.put_bytes(0, buffer);
 
@@ -110,10 +128,12 @@ impl Publisher {
                     .unwrap()
                     .offer_part(src_buffer, 0, buffer_size as i32);
             if let Err(error) = result {
-                error!(
-                    "Send error: {:?}. Retrying ({}/{})",
-                    error, tries, max_retry
-                );
+                if let Some(suppressed) = self.send_errors.admit() {
+                    error!(
+                        suppressed,
+                        "Send error: {:?}. Retrying ({}/{})", error, tries, max_retry
+                    );
+                }
                 std::thread::sleep(Duration::from_millis(100));
             } else {
                 tries = max_retry;
@@ -121,7 +141,12 @@ impl Publisher {
         }
 
         if !publication.clone().lock().unwrap().is_connected() {
-            error!("No active subscribers detected on channel {}", channel);
+            if let Some(suppressed) = self.unsubscribed.admit() {
+                error!(
+                    suppressed,
+                    "No active subscribers detected on channel {}", channel
+                );
+            }
         };
     }
 
@@ -9,9 +9,9 @@ use aeron_rs::{
     aeron::Aeron, concurrent::status::status_indicator_reader::channel_status_to_str,
     context::Context, image::Image, utils::errors::AeronError,
 };
-use log::{debug, error, info};
+use tracing::{debug, error, info};
 
-use crate::aeron::{str_to_c, Settings};
+use crate::aeron::{await_channel, str_to_c, Settings};
 use aeron_rs::utils::types::Index;
 
 pub fn on_new_subscription_handler(channel: CString, stream_id: i32, correlation_id: i64) {
@@ -89,10 +89,19 @@ impl Subscriber {
         })
     }
 
-    pub fn listen(&self) -> Arc<Mutex<Subscription>> {
-        let subscription = self
-            .create_subscription()
-            .expect("Error creating subscription");
+    /// Adds the subscription, failing when the driver refuses it or can't open its channel, such
+    /// as when its port is already bound
+    pub fn listen(&self) -> Result<Arc<Mutex<Subscription>>, String> {
+        let subscription = self.create_subscription().map_err(|e| format!("{:?}", e))?;
+        let counters = self
+            .aeron
+            .borrow()
+            .counters_reader()
+            .map_err(|e| format!("{:?}", e))?;
+        let channel_status_id = subscription.lock().unwrap().channel_status_id();
+        await_channel(&counters, channel_status_id, || {
+            subscription.lock().unwrap().is_closed()
+        })?;
         if subscription.lock().is_err() {
             let channel_status = subscription
                 .lock()
@@ -106,14 +115,14 @@ impl Subscriber {
             );
         }
 
-        subscription
+        Ok(subscription)
     }
 
-    pub fn recv<F>(&self, subscription: Arc<Mutex<Subscription>>, mut on_new_fragment: F)
+    pub fn recv<F>(&self, subscription: Arc<Mutex<Subscription>>, mut on_new_fragment: F) -> i32
     where
-        F: Fn(&AtomicBuffer, Index, Index, &Header),
+        F: FnMut(&AtomicBuffer, Index, Index, &Header),
     {
-        subscription.lock().unwrap().poll(&mut on_new_fragment, 10);
+        subscription.lock().unwrap().poll(&mut on_new_fragment, 10)
     }
 
     fn create_subscription(&self) -> Result<Arc<Mutex<Subscription>>, AeronError> {
@@ -123,7 +132,7 @@ impl Subscriber {
             .expect("Error adding subscription");
 
         let mut subscription = aeron.find_subscription(subscription_id);
-        while subscription.is_err() {
+        while let Err(AeronError::SubscriptionNotReady(_)) = subscription {
             std::thread::yield_now();
             subscription = aeron.find_subscription(subscription_id);
         }
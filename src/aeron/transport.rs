@@ -0,0 +1,169 @@
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use aeron_rs::concurrent::atomic_buffer::AtomicBuffer;
+use aeron_rs::concurrent::logbuffer::header::Header;
+use aeron_rs::image::Image;
+use aeron_rs::publication::Publication as AeronPublicationHandle;
+use aeron_rs::subscription::Subscription as AeronSubscriptionHandle;
+use aeron_rs::utils::types::Index;
+
+use crate::aeron::publisher::Publisher;
+use crate::aeron::subscriber::{self, Subscriber};
+use crate::aeron::{instance_publisher, instance_subscriber, Settings};
+use crate::transport::{FragmentHeader, ImageEvent, Publication, Subscription, Transport};
+
+/// Transport over the Aeron media driver, each publication and subscription having its own client
+pub struct AeronTransport {
+    settings: Settings,
+}
+
+impl AeronTransport {
+    pub fn new(settings: &Settings) -> Self {
+        AeronTransport {
+            settings: settings.clone(),
+        }
+    }
+
+    /// Settings of a channel, on the stream it names if any, and its URI as given to the driver
+    fn stream(&self, channel: &str) -> (Settings, String) {
+        let mut settings = self.settings.clone();
+        let (media, params) = channel.split_once('?').unwrap_or((channel, ""));
+        let params: Vec<&str> = params
+            .split('|')
+            .filter(|param| match param.strip_prefix("stream-id=") {
+                Some(stream_id) => {
+                    settings.stream_id = stream_id.parse().expect("Cannot parse stream id");
+                    false
+                }
+                None => !param.is_empty(),
+            })
+            .collect();
+        let channel = match params.as_slice() {
+            [] => media.to_owned(),
+            params => format!("{}?{}", media, params.join("|")),
+        };
+        (settings, channel)
+    }
+}
+
+impl Transport for AeronTransport {
+    fn try_publication(&self, channel: &str) -> Result<Box<dyn Publication>, String> {
+        let (settings, channel) = self.stream(channel);
+        let (publisher, publication) =
+            instance_publisher(Publisher::new_context(&settings), &settings, &channel)?;
+        Ok(Box::new(AeronPublication {
+            publisher,
+            publication,
+        }))
+    }
+
+    fn try_subscription(&self, channel: &str) -> Result<Box<dyn Subscription>, String> {
+        let (settings, channel) = self.stream(channel);
+        let events: Arc<Mutex<Vec<ImageEvent>>> = Arc::new(Mutex::new(vec![]));
+        let mut context = Subscriber::new_context(&settings);
+        let available_events = events.clone();
+        context.set_available_image_handler(move |image: &Image| {
+            subscriber::available_image_handler(image);
+            available_events
+                .lock()
+                .unwrap()
+                .push(ImageEvent::Available {
+                    session_id: image.session_id(),
+                    source_identity: image.source_identity().to_string_lossy().into_owned(),
+                    correlation_id: image.correlation_id(),
+                });
+        });
+        let unavailable_events = events.clone();
+        context.set_unavailable_image_handler(move |image: &Image| {
+            subscriber::unavailable_image_handler(image);
+            unavailable_events
+                .lock()
+                .unwrap()
+                .push(ImageEvent::Unavailable {
+                    session_id: image.session_id(),
+                });
+        });
+        let (subscriber, subscription) = instance_subscriber(context, &settings, &channel)?;
+        Ok(Box::new(AeronSubscription {
+            subscriber,
+            subscription,
+            events,
+        }))
+    }
+}
+
+struct AeronPublication {
+    publisher: Publisher,
+    publication: Arc<Mutex<AeronPublicationHandle>>,
+}
+
+impl Publication for AeronPublication {
+    fn publish(&self, buffer: &[u8]) {
+        self.publisher
+            .send(self.publication.to_owned(), buffer, buffer.len())
+    }
+
+    fn session_id(&self) -> i32 {
+        self.publication.lock().unwrap().session_id()
+    }
+
+    fn stream_id(&self) -> i32 {
+        self.publication.lock().unwrap().stream_id()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.publication.lock().unwrap().is_connected()
+    }
+
+    fn close(&self) {
+        self.publication.lock().unwrap().close()
+    }
+}
+
+struct AeronSubscription {
+    subscriber: Subscriber,
+    subscription: Arc<Mutex<AeronSubscriptionHandle>>,
+    events: Arc<Mutex<Vec<ImageEvent>>>,
+}
+
+impl Subscription for AeronSubscription {
+    fn poll(&self, on_fragment: &mut dyn FnMut(&[u8], &FragmentHeader)) -> usize {
+        let fragments = self.subscriber.recv(
+            self.subscription.to_owned(),
+            |buffer: &AtomicBuffer, offset: Index, length: Index, header: &Header| {
+                let fragment = unsafe {
+                    slice::from_raw_parts(buffer.buffer().offset(offset as isize), length as usize)
+                };
+                on_fragment(
+                    fragment,
+                    &FragmentHeader {
+                        session_id: header.session_id(),
+                        stream_id: header.stream_id(),
+                    },
+                )
+            },
+        );
+        fragments.max(0) as usize
+    }
+
+    fn has_image(&self, session_id: i32) -> bool {
+        self.subscription
+            .lock()
+            .unwrap()
+            .image_by_session_id(session_id)
+            .is_some()
+    }
+
+    fn image_events(&self) -> Vec<ImageEvent> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+
+    fn registration_id(&self) -> i64 {
+        self.subscription.lock().unwrap().registration_id()
+    }
+
+    fn close(&self) {
+        self.subscription.lock().unwrap().close_and_remove_images();
+    }
+}
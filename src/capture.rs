@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{error, info};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_RAW: u16 = 101;
+const OPTION_END: u16 = 0;
+const OPTION_COMMENT: u16 = 1;
+const OPTION_SHB_USER_APPLICATION: u16 = 4;
+const OPTION_IF_NAME: u16 = 2;
+const OPTION_EPB_FLAGS: u16 = 2;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Longest time the captured packets stay buffered before being written to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the tunnel and delivered to the endpoint
+    Inbound,
+    /// Received from the endpoint and published into the tunnel
+    Outbound,
+}
+
+impl Direction {
+    fn flags(&self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+/// Writes the datagrams flowing through the tunnel to a pcapng file, wrapped into synthetic
+/// IP/UDP headers so that they can be dissected by Wireshark. Each packet is annotated with
+/// direction, slot and session id in its comment. Packets are buffered, and written out when
+/// flushed periodically and on drop.
+pub struct Capture {
+    writer: Mutex<BufWriter<File>>,
+    flushed: Mutex<Instant>,
+}
+
+impl Capture {
+    pub fn create(path: &str) -> Self {
+        let file = File::create(path).expect("Error creating capture file");
+        let capture = Capture {
+            writer: Mutex::new(BufWriter::new(file)),
+            flushed: Mutex::new(Instant::now()),
+        };
+        capture.write_block(BLOCK_SECTION_HEADER, &section_header());
+        capture.write_block(BLOCK_INTERFACE_DESCRIPTION, &interface_description());
+        info!("Capturing tunneled datagrams to {}", path);
+        capture
+    }
+
+    pub fn record(
+        &self,
+        direction: Direction,
+        slot: Option<usize>,
+        session_id: i32,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) {
+        let packet = udp_packet(source, destination, payload);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_micros() as u64)
+            .unwrap_or(0);
+        let comment = match slot {
+            Some(slot) => format!(
+                "direction={} slot={} session={}",
+                direction.name(),
+                slot + 1,
+                session_id
+            ),
+            None => format!("direction={} session={}", direction.name(), session_id),
+        };
+
+        let mut body = vec![];
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+        pad(&mut body);
+        push_option(&mut body, OPTION_COMMENT, comment.as_bytes());
+        push_option(
+            &mut body,
+            OPTION_EPB_FLAGS,
+            &direction.flags().to_le_bytes(),
+        );
+        push_option(&mut body, OPTION_END, &[]);
+        self.write_block(BLOCK_ENHANCED_PACKET, &body);
+    }
+
+    fn write_block(&self, block_type: u32, body: &[u8]) {
+        let total_length = (body.len() + 12) as u32;
+        let mut block = Vec::with_capacity(total_length as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_length.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_length.to_le_bytes());
+
+        if let Err(e) = self.writer.lock().unwrap().write_all(&block) {
+            error!("Can't write to capture file: {}", e);
+        }
+    }
+
+    /// Writes the buffered packets to the file when the last flush is older than the interval
+    pub fn flush_periodically(&self) {
+        let mut flushed = self.flushed.lock().unwrap();
+        if flushed.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+            *flushed = Instant::now();
+        }
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.writer.lock().unwrap().flush() {
+            error!("Can't write to capture file: {}", e);
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    push_option(
+        &mut body,
+        OPTION_SHB_USER_APPLICATION,
+        concat!("rudp-tunnel ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    push_option(&mut body, OPTION_END, &[]);
+    body
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    push_option(&mut body, OPTION_IF_NAME, b"rudp-tunnel");
+    push_option(&mut body, OPTION_END, &[]);
+    body
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+}
+
+fn udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_length = (payload.len() + 8) as u16;
+    let mut packet = vec![];
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            let mut header = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, IP_PROTOCOL_UDP, 0, 0];
+            header[2..4].copy_from_slice(&(udp_length + 20).to_be_bytes());
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&destination_ip.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend(header);
+        }
+        (source_ip, destination_ip) => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_length.to_be_bytes());
+            packet.extend_from_slice(&[IP_PROTOCOL_UDP, 64]);
+            packet.extend_from_slice(&to_ipv6(source_ip).octets());
+            packet.extend_from_slice(&to_ipv6(destination_ip).octets());
+        }
+    }
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&udp_length.to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn test_capture_blocks() {
+        let mut path = temp_dir();
+        path.push(format!("rudp-tunnel-capture-{}.pcapng", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+
+        let capture = Capture::create(&path);
+        capture.record(
+            Direction::Outbound,
+            Some(0),
+            42,
+            "10.0.0.1:1000".parse().unwrap(),
+            "10.0.0.2:2000".parse().unwrap(),
+            b"hello",
+        );
+        // Packets are buffered until flushed, at the latest on drop
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        drop(capture);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut offset = 0;
+        let mut block_types = vec![];
+        while offset < bytes.len() {
+            let block_type = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            let length = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+            assert!(length.is_multiple_of(4));
+            block_types.push(block_type);
+            offset += length as usize;
+        }
+        assert_eq!(offset, bytes.len());
+        assert_eq!(
+            block_types,
+            vec![
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ipv4_checksum() {
+        let packet = udp_packet(
+            "10.0.0.1:1000".parse().unwrap(),
+            "10.0.0.2:2000".parse().unwrap(),
+            b"hello",
+        );
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+    }
+}
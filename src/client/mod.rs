@@ -1,192 +1,596 @@
-use std::net::SocketAddr;
+mod paths;
+mod ping;
+mod servers;
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
-use std::{io, slice};
-
-use aeron_rs::concurrent::atomic_buffer::AtomicBuffer;
-use aeron_rs::concurrent::logbuffer::header::Header;
-use aeron_rs::utils::types::Index;
-use log::{debug, error, info};
-
-use crate::aeron::publisher::Publisher;
-use crate::aeron::subscriber::Subscriber;
-use crate::aeron::{instance_publisher, instance_subscriber, Settings};
-use crate::messages::{Failure, HandshakeRequest, HandshakeResponse};
-use crate::{attach_endpoint, Arguments, Timeout};
-use std::cell::RefCell;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::aeron::transport::AeronTransport;
+use crate::aeron::Settings;
+use crate::capture::{Capture, Direction};
+use crate::compression::{decompress, Compressor};
+use crate::deadline::Deadline;
+use crate::fec::Fec;
+use crate::latency::Pinger;
+use crate::logging::Throttle;
+use crate::messages::{
+    Capabilities, Compression, Datagram, Failure, Frame, HandshakeReply, HandshakeRequest,
+    HandshakeResponse, Origin, Reliability, PROTOCOL_VERSION,
+};
+use crate::multipath::{self, Multipath};
+use crate::systemd::Notifier;
+use crate::transport::{FragmentHeader, ImageEvent, Transport};
+use crate::{attach_endpoint, endpoint_packet, ipc_channel, Arguments, Timeout};
+use paths::{Path, Paths};
+pub use servers::ServerSelection;
+use servers::Servers;
+use std::cell::{Cell, RefCell};
 use std::ops::Add;
 use std::str::FromStr;
 
 pub struct Client {
     settings: Settings,
-    channel_forward: String,
-    channel_backward: String,
+    servers: RefCell<Servers>,
     args: Arguments,
+    transport: Box<dyn Transport>,
+    /// Handshake publication and subscription to a single port server, kept for the session
+    handshake_path: RefCell<Option<Path>>,
+    notifier: Notifier,
+    send_errors: Throttle,
+    receive_errors: Throttle,
 }
 
 impl Client {
     pub fn instance(args: &Arguments) -> Self {
+        let settings = Settings::new(args);
+        let transport = AeronTransport::new(&settings);
+        Client::new(args, settings, Box::new(transport))
+    }
+
+    pub fn new(args: &Arguments, settings: Settings, transport: Box<dyn Transport>) -> Self {
         Client {
-            settings: Settings::new(args),
-            channel_forward: format!("aeron:udp?{}", args.cforward),
-            channel_backward: format!("aeron:udp?{}", args.cbackward),
+            settings,
+            servers: RefCell::new(Servers::new(
+                args.servers.clone(),
+                args.server_selection,
+                args.max_redirects,
+            )),
             args: args.clone(),
+            transport,
+            handshake_path: RefCell::new(None),
+            notifier: Notifier::from_env(),
+            send_errors: Throttle::default(),
+            receive_errors: Throttle::default(),
         }
     }
 
     pub fn start(self, running: Arc<AtomicBool>) {
-        match self.handshake(&running) {
-            Ok(connection) => {
-                info!("Connection parameters: {:?}", connection);
+        let socket = attach_endpoint(&self.args);
+        let capture = self.args.capture.as_ref().map(|path| Capture::create(path));
+        while running.load(Ordering::SeqCst) {
+            let server = self.servers.borrow().active().to_owned();
+            match self.handshake(&running) {
+                Some(Ok(connection)) => {
+                    info!(
+                        "Active server {}, connection parameters: {:?}",
+                        server, connection
+                    );
+                    if Reliability::negotiated(connection.capabilities) != self.args.reliability {
+                        warn!("Server refused best-effort delivery, falling back to reliable");
+                    }
+                    if self.session(&running, &connection, &socket, &capture) {
+                        let next = self.servers.borrow_mut().fail_over().to_owned();
+                        warn!(
+                            "Server {} stopped responding, reconnecting to {}",
+                            server, next
+                        );
+                    }
+                }
+                Some(Err(Failure::HandshakeRedirect(_, redirect)))
+                    if self.servers.borrow_mut().redirect(redirect.to_string()) =>
+                {
+                    info!("Server {} redirected to server {}", server, redirect);
+                }
+                Some(Err(failure)) => {
+                    match failure {
+                        Failure::HandshakeFailedIncompatibleVersion(_, version) => error!(
+                            "Handshake failed: server {} protocol version {} is incompatible with {}",
+                            server, version, PROTOCOL_VERSION
+                        ),
+                        failure => error!("Handshake with server {} failed: {:?}", server, failure),
+                    }
+                    let mut servers = self.servers.borrow_mut();
+                    if !servers.can_fail_over() {
+                        break;
+                    }
+                    let next = servers.fail_over().to_owned();
+                    if servers.exhausted() {
+                        error!("Every server failed in a row, giving up");
+                        break;
+                    }
+                    info!("Failing over to server {}", next);
+                }
+                None if running.load(Ordering::SeqCst) => {
+                    let next = self.servers.borrow_mut().fail_over().to_owned();
+                    info!("Failing over to server {}", next);
+                }
+                None => break,
+            }
+        }
+        self.notifier.stopping();
+    }
 
-                let channel_forward = format!(
-                    "aeron:udp?endpoint={}:{}{}",
-                    self.args.server, connection.port, self.args.interface
-                );
-                let channel_backward = format!(
-                    "aeron:udp?endpoint=0.0.0.0:0{}|control={}:{}|control-mode=dynamic",
-                    self.args.interface, self.args.server, connection.control
-                );
+    /// Tunnels the traffic through the slot assigned by the server, until stopped or, when
+    /// heartbeats were negotiated, the server is not heard from for too long, returning true
+    fn session(
+        &self,
+        running: &Arc<AtomicBool>,
+        connection: &HandshakeResponse,
+        socket: &UdpSocket,
+        capture: &Option<Capture>,
+    ) -> bool {
+        let multipath = connection
+            .capabilities
+            .contains(Capabilities::MULTIPATH)
+            .then(Multipath::default);
+        let mut interfaces = self.interfaces();
+        if multipath.is_none() {
+            interfaces.truncate(1);
+        }
+        let paths = self.open_paths(connection, &interfaces);
+        let stream_id = paths.primary().stream_id();
+        let session_id = paths.primary().session_id();
+        let _span = info_span!(
+            "session",
+            server = %self.server(),
+            port = connection.port,
+            session_id,
+            stream_id
+        )
+        .entered();
 
-                let subscriber_context = Subscriber::new_context(&self.settings);
-                let (subscriber, subscription) =
-                    instance_subscriber(subscriber_context, &self.settings, &channel_backward);
-
-                let publisher_context = Publisher::new_context(&self.settings);
-                let (publisher, publication) =
-                    instance_publisher(publisher_context, &self.settings, &channel_forward);
-                let stream_id = publication.lock().unwrap().stream_id();
-                let session_id = publication.lock().unwrap().session_id();
-
-                let socket = attach_endpoint(&self.args);
-                let on_new_fragment =
-                    |buffer: &AtomicBuffer, offset: Index, length: Index, header: &Header| {
-                        let peer_addr = socket
-                            .peer_addr()
-                            .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
-                        debug!(
-                        "Sending {} bytes received on stream {} from session {} to endpoint {:?}",
-                        length,
-                        header.stream_id(),
-                        header.session_id(),
-                        peer_addr
+        let compressor = Compressor::new(connection.compression, self.args.compression_threshold);
+        let deadline = Deadline::new(self.args.max_age, connection.capabilities);
+        let fec = Fec::new(self.args.fec, connection.capabilities);
+        let heartbeat_interval = connection
+            .capabilities
+            .contains(Capabilities::HEARTBEAT)
+            .then(|| Duration::from_millis(connection.heartbeat_interval));
+        let pinger = RefCell::new(
+            connection
+                .capabilities
+                .contains(Capabilities::PING)
+                .then(|| Pinger::new(Duration::from_millis(self.args.ping_interval))),
+        );
+        let last_sent = Cell::new(Instant::now());
+        let last_received = Cell::new(Instant::now());
+        let send = |frame: &Frame| {
+            paths.publish(&bincode::serialize(frame).unwrap());
+            last_sent.set(Instant::now());
+        };
+        let mut on_new_fragment = |buffer: &[u8], header: &FragmentHeader| {
+            last_received.set(Instant::now());
+            let frames = match bincode::deserialize::<Frame>(buffer) {
+                Ok(frame) => fec.decode(frame),
+                Err(e) => {
+                    error!(
+                        "Discarding malformed datagram from session {}: {}",
+                        header.session_id, e
                     );
-                        if socket.peer_addr().is_ok() {
-                            unsafe {
-                                let slice_msg = slice::from_raw_parts_mut(
-                                    buffer.buffer().offset(offset as isize),
-                                    length as usize,
-                                );
-                                socket.send(slice_msg).unwrap_or_else(|e| {
-                                    error!("Can't tunnel packets to server: {}", e);
-                                    0
-                                });
-                            }
+                    return;
+                }
+            };
+            for frame in frames {
+                // Datagrams are normalized into their multipath sequence and deadline, if any
+                let carried = match frame {
+                    Frame::Datagram(datagram) => Some((None, None, datagram)),
+                    Frame::Expiring {
+                        deadline: at,
+                        datagram,
+                    } => Some((None, Some(at), datagram)),
+                    Frame::Sequenced {
+                        sequence,
+                        deadline: at,
+                        datagram,
+                    } => Some((Some(sequence), at, datagram)),
+                    Frame::Ping {
+                        sequence,
+                        timestamp,
+                    } => {
+                        send(&Frame::Pong {
+                            sequence,
+                            timestamp,
+                        });
+                        None
+                    }
+                    Frame::Pong { timestamp, .. } => {
+                        if let Some(pinger) = pinger.borrow_mut().as_mut() {
+                            pinger.pong(timestamp);
                         }
-                    };
-
-                info!(
-                    "Client {} to endpoint {} ",
-                    if self.args.listen {
-                        "listening"
-                    } else {
-                        "connected"
-                    },
-                    self.args.endpoint
+                        None
+                    }
+                    // Heartbeats carry nothing, and shards within shards are never sent
+                    Frame::Heartbeat | Frame::FecData { .. } | Frame::FecParity { .. } => None,
+                };
+                let Some((sequence, at, datagram)) = carried else {
+                    debug!("Received control frame from session {}", header.session_id);
+                    continue;
+                };
+                if sequence.is_some_and(|sequence| {
+                    multipath
+                        .as_ref()
+                        .is_some_and(|multipath| multipath.is_duplicate(sequence))
+                }) {
+                    debug!(
+                        "Dropping duplicate datagram from session {}",
+                        header.session_id
+                    );
+                    continue;
+                }
+                if at.is_some_and(|at| deadline.is_stale(at)) {
+                    debug!("Dropping stale datagram from session {}", header.session_id);
+                    continue;
+                }
+                let datagram = match decompress(datagram) {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        error!(
+                            "Discarding malformed datagram from session {}: {}",
+                            header.session_id, e
+                        );
+                        continue;
+                    }
+                };
+                let peer_addr = socket
+                    .peer_addr()
+                    .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
+                debug!(
+                    "Sending {} bytes from stream {} session {} {:?} to endpoint {:?}",
+                    datagram.payload.len(),
+                    header.stream_id,
+                    header.session_id,
+                    datagram.metadata,
+                    peer_addr
                 );
-
-                while running.load(Ordering::SeqCst) {
-                    let mut recv_buff = vec![0; self.settings.message_length as usize];
-                    match socket.recv_from(&mut recv_buff) {
-                        Ok((n, addr)) => {
-                            debug!("Publishing on stream {} from session {} {} bytes received from endpoint {:?}", stream_id, session_id, n, addr);
-                            socket
-                                .connect(addr)
-                                .expect("Error connecting socket output");
-                            publisher.send(publication.to_owned(), &recv_buff, n);
+                if let (Some(capture), Ok(peer_addr)) = (capture, socket.peer_addr()) {
+                    capture.record(
+                        Direction::Inbound,
+                        None,
+                        header.session_id,
+                        datagram.metadata.source,
+                        peer_addr,
+                        &datagram.payload,
+                    );
+                }
+                if socket.peer_addr().is_ok() {
+                    let local_addr = socket
+                        .local_addr()
+                        .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
+                    let packet = endpoint_packet(&self.args, &datagram, local_addr);
+                    socket.send(&packet).unwrap_or_else(|e| {
+                        if let Some(suppressed) = self.send_errors.admit() {
+                            error!(suppressed, "Can't tunnel packets to server: {}", e);
                         }
-                        Err(err) => {
-                            if err.kind() != io::ErrorKind::WouldBlock {
-                                error!("Error receiving from endpoint: {:?}", err)
-                            }
+                        0
+                    });
+                }
+            }
+        };
+
+        self.notifier
+            .status(&format!("Connected to server {}", self.server()));
+        info!(
+            "Client {} to endpoint {} ",
+            if self.args.listen {
+                "listening"
+            } else {
+                "connected"
+            },
+            self.args.endpoint
+        );
+
+        let mut server_dead = false;
+        let mut statistics_timeout =
+            SystemTime::now().add(Duration::from_secs(Timeout::STATISTICS_SECONDS));
+        while running.load(Ordering::SeqCst) {
+            if statistics_timeout < SystemTime::now() {
+                statistics_timeout =
+                    statistics_timeout.add(Duration::from_secs(Timeout::STATISTICS_SECONDS));
+                if compressor.compression() != Compression::None {
+                    info!(
+                        "{:?} compression: {}",
+                        compressor.compression(),
+                        compressor.stats()
+                    );
+                }
+                if let Some(multipath) = multipath.as_ref().filter(|m| m.duplicates() > 0) {
+                    info!("Dropped {} duplicate datagrams", multipath.duplicates());
+                }
+                if deadline.stale() > 0 {
+                    info!("Dropped {} stale datagrams", deadline.stale());
+                }
+                if fec.has_losses() {
+                    info!("Error correction: {}", fec);
+                }
+                if let Some(pinger) = pinger.borrow_mut().as_mut() {
+                    if !pinger.stats.is_empty() {
+                        info!("Round trip time: {}", pinger.stats);
+                        pinger.stats.reset();
+                    }
+                }
+            }
+
+            let mut recv_buff = vec![0; self.settings.message_length as usize];
+            match socket.recv_from(&mut recv_buff) {
+                Ok((n, addr)) => {
+                    debug!("Publishing on stream {} from session {} {} bytes received from endpoint {:?}", stream_id, session_id, n, addr);
+                    socket
+                        .connect(addr)
+                        .expect("Error connecting socket output");
+                    let datagram = Datagram::new(Origin::Client(session_id), addr, &recv_buff[..n]);
+                    if let Some(capture) = capture {
+                        let local_addr = socket
+                            .local_addr()
+                            .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
+                        capture.record(
+                            Direction::Outbound,
+                            None,
+                            session_id,
+                            addr,
+                            local_addr,
+                            &datagram.payload,
+                        );
+                    }
+                    let frame = multipath::frame(
+                        compressor.compress(&datagram),
+                        &deadline,
+                        multipath.as_ref(),
+                    );
+                    fec.encode(frame).iter().for_each(send);
+                }
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::WouldBlock {
+                        if let Some(suppressed) = self.receive_errors.admit() {
+                            error!(suppressed, "Error receiving from endpoint: {:?}", err)
                         }
                     }
+                }
+            }
 
-                    subscriber.recv(subscription.to_owned(), on_new_fragment);
+            paths.poll(&mut on_new_fragment);
 
-                    std::thread::sleep(Duration::from_millis(1));
+            let ping = pinger
+                .borrow_mut()
+                .as_mut()
+                .and_then(|pinger| pinger.ping());
+            if let Some(ping) = ping {
+                send(&ping);
+            }
+            fec.flush().iter().for_each(send);
+            if let Some(interval) = heartbeat_interval {
+                if last_sent.get().elapsed() >= interval {
+                    paths.publish_all(&bincode::serialize(&Frame::Heartbeat).unwrap());
+                    last_sent.set(Instant::now());
+                }
+                if last_received.get().elapsed() >= interval * self.args.heartbeat_misses {
+                    server_dead = true;
+                    break;
                 }
             }
-            Err(failure) => error!("Handshake failed: {:?}", failure),
+            self.notifier.watchdog();
+
+            std::thread::sleep(Duration::from_millis(1));
         }
+
+        paths.close();
+        server_dead
     }
 
-    fn handshake(&self, running: &Arc<AtomicBool>) -> Result<HandshakeResponse, Failure> {
-        let handshake_request = HandshakeRequest::new();
-        info!("Starting handshake: {:?}", handshake_request);
+    /// Routing parameters of the interfaces the client sends through, the primary one first
+    fn interfaces(&self) -> Vec<String> {
+        if self.args.interfaces.is_empty() {
+            vec![self.args.interface.clone()]
+        } else {
+            self.args.interfaces.clone()
+        }
+    }
 
-        let subscriber_context = Subscriber::new_context(&self.settings);
-        let (subscriber, subscription) =
-            instance_subscriber(subscriber_context, &self.settings, &self.channel_backward);
+    /// Opens the paths to the slot assigned by the server through the given interfaces, or takes
+    /// over the handshake one of a single port server
+    fn open_paths(&self, connection: &HandshakeResponse, interfaces: &[String]) -> Paths {
+        match self.handshake_path.take() {
+            Some(path) => Paths::opened(vec![path], self.args.multipath),
+            None => Paths::new(
+                self.transport.as_ref(),
+                interfaces
+                    .iter()
+                    .map(|interface| self.slot_channels(connection, interface))
+                    .collect(),
+                self.args.multipath,
+            ),
+        }
+    }
+
+    /// Channels of the slot assigned by the server through an interface, forward and backward
+    fn slot_channels(&self, connection: &HandshakeResponse, interface: &str) -> (String, String) {
+        if self.args.ipc {
+            return (
+                ipc_channel(connection.port),
+                ipc_channel(connection.control),
+            );
+        }
+        let reliability = Reliability::negotiated(connection.capabilities);
+        let server = self.server();
+        (
+            format!(
+                "aeron:udp?endpoint={}:{}{}",
+                server, connection.port, interface
+            ),
+            format!(
+                "aeron:udp?endpoint=0.0.0.0:0{}|control={}:{}|control-mode=dynamic{}",
+                interface,
+                server,
+                connection.control,
+                reliability.channel_parameter()
+            ),
+        )
+    }
 
-        let publisher_context = Publisher::new_context(&self.settings);
-        let (publisher, publication) =
-            instance_publisher(publisher_context, &self.settings, &self.channel_forward);
-        let stream_id = publication.lock().unwrap().session_id();
-        let session_id = publication.lock().unwrap().session_id();
+    /// Server currently connected to, or being handshaken with
+    fn server(&self) -> String {
+        self.servers.borrow().active().to_owned()
+    }
+
+    /// Channels of the handshake with the active server, forward and backward, single port
+    /// servers responding on the backward one
+    fn handshake_channels(&self) -> (String, String) {
+        if self.args.ipc {
+            return (ipc_channel(self.args.port), ipc_channel(self.args.control));
+        }
+        let server = self.server();
+        (
+            format!(
+                "aeron:udp?endpoint={}:{}{}",
+                server, self.args.port, self.args.interface
+            ),
+            if self.args.single_port {
+                format!(
+                    "aeron:udp?control-mode=response|control={}:{}{}",
+                    server, self.args.control, self.args.interface
+                )
+            } else {
+                format!(
+                    "aeron:udp?endpoint=0.0.0.0:0{}|control={}:{}|control-mode=dynamic",
+                    self.args.interface, server, self.args.control
+                )
+            },
+        )
+    }
+
+    /// Returns the outcome of the handshake with the active server, none if stopped before or,
+    /// when there is another server to fail over to, if timed out
+    pub(crate) fn handshake(
+        &self,
+        running: &Arc<AtomicBool>,
+    ) -> Option<Result<HandshakeResponse, Failure>> {
+        let _span = info_span!("handshake", server = %self.server()).entered();
+        let handshake_request = HandshakeRequest {
+            capabilities: [
+                (Capabilities::HEARTBEAT, self.args.heartbeat_interval == 0),
+                (Capabilities::PING, self.args.ping_interval == 0),
+                (
+                    Capabilities::BEST_EFFORT,
+                    self.args.reliability == Reliability::Reliable
+                        || self.args.single_port
+                        || self.args.ipc,
+                ),
+                (
+                    Capabilities::MULTIPATH,
+                    self.interfaces().len() < 2 || self.args.single_port || self.args.ipc,
+                ),
+                (Capabilities::REDIRECT, self.args.max_redirects == 0),
+            ]
+            .into_iter()
+            .filter(|(_, disabled)| *disabled)
+            .fold(Capabilities::SUPPORTED, |capabilities, (disabled, _)| {
+                capabilities.without(disabled)
+            }),
+            compression: self.args.compression.clone(),
+            identity: self.args.identity.clone(),
+            ..HandshakeRequest::new()
+        };
+        info!("Starting handshake: {:?}", handshake_request);
+
+        let (channel_forward, channel_backward) = self.handshake_channels();
+        let subscription = self.transport.subscription(&channel_backward);
+        self.notifier
+            .ready(&format!("Handshaking with server {}", self.server()));
+        let channel_forward = if self.args.single_port {
+            format!(
+                "{}|response-correlation-id={}",
+                channel_forward,
+                subscription.registration_id()
+            )
+        } else {
+            channel_forward
+        };
+        let publication = self.transport.publication(&channel_forward);
+        let stream_id = publication.stream_id();
+        let session_id = publication.session_id();
+        let started = Instant::now();
+        let timeout = self
+            .servers
+            .borrow()
+            .can_fail_over()
+            .then_some(self.settings.handshake_timeout);
+        let mut first_sent: Option<Instant> = None;
 
         let mut handshake_request_timeout = SystemTime::now();
         let handshake_response: RefCell<Option<Result<HandshakeResponse, Failure>>> =
             RefCell::new(None);
-        let on_handshake_response = |buffer: &AtomicBuffer,
-                                     offset: Index,
-                                     length: Index,
-                                     header: &Header| {
-            unsafe {
-                let slice_msg = slice::from_raw_parts_mut(
-                    buffer.buffer().offset(offset as isize),
-                    length as usize,
-                );
-                debug!(
-                    "Received handshake response from stream_id={} session_id={} (length={})",
-                    header.stream_id(),
-                    header.session_id(),
-                    length
-                );
-                let deserialized: Result<HandshakeResponse, Failure> =
-                    bincode::deserialize(slice_msg).unwrap();
-                let encrypted_session_id = session_id.wrapping_mul(handshake_request.key);
-                debug!(
-                    "Computing verification: {} * {} = {}",
-                    session_id, handshake_request.key, encrypted_session_id
-                );
-                match deserialized {
-                    Ok(response) => {
-                        if response.verification == encrypted_session_id {
+        let mut on_handshake_response = |buffer: &[u8], header: &FragmentHeader| {
+            debug!(
+                "Received handshake response from stream_id={} session_id={} (length={})",
+                header.stream_id,
+                header.session_id,
+                buffer.len()
+            );
+            // Single port servers may follow the response with the first frames of the session
+            if handshake_response.borrow().is_some() {
+                return;
+            }
+            let Some(deserialized) = HandshakeReply::decode(buffer) else {
+                error!("Ignoring handshake response of unknown protocol");
+                return;
+            };
+            let encrypted_session_id = session_id.wrapping_mul(handshake_request.key);
+            debug!(
+                "Computing verification: {} * {} = {}",
+                session_id, handshake_request.key, encrypted_session_id
+            );
+            match deserialized {
+                Ok(response) => {
+                    if response.verification == encrypted_session_id {
+                        handshake_response.replace(Some(deserialized));
+                    } else {
+                        debug!("Ignoring handshake success, verification mismatch (local={}, received={})", encrypted_session_id, response.verification);
+                    }
+                }
+                Err(failure) => match failure {
+                    Failure::HandshakeFailedServerFull(failure_details)
+                    | Failure::HandshakeFailedTooManyConnections(failure_details)
+                    | Failure::HandshakeFailedQuotaExceeded(failure_details)
+                    | Failure::HandshakeFailedNotAllowed(failure_details)
+                    | Failure::HandshakeFailedIncompatibleVersion(failure_details, _)
+                    | Failure::HandshakeRedirect(failure_details, _) => {
+                        if failure_details.session_id == encrypted_session_id {
                             handshake_response.replace(Some(deserialized));
                         } else {
-                            debug!("Ignoring handshake success, verification mismatch (local={}, received={})", encrypted_session_id, response.verification);
+                            debug!("Ignoring handshake failure, verification mismatch (local={}, received={})", encrypted_session_id, failure_details.session_id)
                         }
                     }
-                    Err(failure) => match failure {
-                        Failure::HandshakeFailedServerFull(failure_details)
-                        | Failure::HandshakeFailedTooManyConnections(failure_details) => {
-                            if failure_details.session_id == encrypted_session_id {
-                                handshake_response.replace(Some(deserialized));
-                            } else {
-                                debug!("Ignoring handshake failure, verification mismatch (local={}, received={})", encrypted_session_id, failure_details.session_id)
-                            }
-                        }
-                    },
-                }
+                },
             }
         };
 
+        // Requests are sent once the server publication reached this node, or responses are lost
+        let mut server_connected = false;
         while running.load(Ordering::SeqCst) && handshake_response.borrow().is_none() {
-            if handshake_request_timeout < SystemTime::now() {
-                handshake_request_timeout = handshake_request_timeout
-                    .add(Duration::from_secs(Timeout::HANDSHAKE_RETRY_SECONDS));
+            if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                warn!("Handshake with server {} timed out", self.server());
+                break;
+            }
+            server_connected |= subscription
+                .image_events()
+                .iter()
+                .any(|event| matches!(event, ImageEvent::Available { .. }));
+            if server_connected && handshake_request_timeout < SystemTime::now() {
+                handshake_request_timeout = SystemTime::now().add(self.settings.handshake_retry);
                 let message = bincode::serialize(&handshake_request).unwrap();
                 info!(
                     "Sending handshake request on stream_id={} session_id={} (length={})",
@@ -194,15 +598,25 @@ impl Client {
                     session_id,
                     message.len()
                 );
-                publisher.send(publication.to_owned(), message.as_ref(), message.len());
+                publication.publish(&message);
+                first_sent.get_or_insert_with(Instant::now);
             }
-            subscriber.recv(subscription.to_owned(), on_handshake_response);
+            subscription.poll(&mut on_handshake_response);
+            self.notifier.watchdog();
             std::thread::sleep(Duration::from_millis(1));
         }
 
-        subscription.lock().unwrap().close_and_remove_images();
-        publication.lock().unwrap().close();
-        let result = handshake_response.borrow().unwrap();
-        result
+        let handshake_response = handshake_response.into_inner();
+        if self.args.single_port && matches!(handshake_response, Some(Ok(_))) {
+            self.handshake_path
+                .replace(Some((publication, subscription)));
+        } else {
+            subscription.close();
+            publication.close();
+        }
+        if let (Some(Ok(_)), Some(first_sent)) = (&handshake_response, first_sent) {
+            self.servers.borrow_mut().connected(first_sent.elapsed());
+        }
+        handshake_response
     }
 }
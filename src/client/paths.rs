@@ -0,0 +1,106 @@
+use std::cell::Cell;
+
+use crate::multipath::MultipathMode;
+use crate::transport::{FragmentHeader, Publication, Subscription, Transport};
+
+/// Publication to, and subscription from, the slot through one interface
+pub(super) type Path = (Box<dyn Publication>, Box<dyn Subscription>);
+
+/// Publications to, and subscriptions from, the slot assigned by the server, one of each per
+/// interface the client sends through, the first being the primary path
+pub(super) struct Paths {
+    mode: MultipathMode,
+    publications: Vec<Box<dyn Publication>>,
+    subscriptions: Vec<Box<dyn Subscription>>,
+    next: Cell<usize>,
+}
+
+impl Paths {
+    /// Opens the paths given their forward and backward channels
+    pub fn new(
+        transport: &dyn Transport,
+        channels: Vec<(String, String)>,
+        mode: MultipathMode,
+    ) -> Self {
+        Paths::opened(
+            channels
+                .iter()
+                .map(|(forward, backward)| {
+                    (
+                        transport.publication(forward),
+                        transport.subscription(backward),
+                    )
+                })
+                .collect(),
+            mode,
+        )
+    }
+
+    /// Takes over paths already opened, such as the handshake one to a single port server
+    pub fn opened(paths: Vec<Path>, mode: MultipathMode) -> Self {
+        let (publications, subscriptions) = paths.into_iter().unzip();
+        Paths {
+            mode,
+            publications,
+            subscriptions,
+            next: Cell::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> &dyn Publication {
+        self.publications[0].as_ref()
+    }
+
+    /// Paths a subscriber is receiving from, all of them when there is only one
+    fn connected(&self) -> Vec<&dyn Publication> {
+        self.publications
+            .iter()
+            .map(|publication| publication.as_ref())
+            .filter(|publication| self.publications.len() == 1 || publication.is_connected())
+            .collect()
+    }
+
+    /// Publishes a datagram on the connected paths the mode selects, or on the primary one when
+    /// none is connected
+    pub fn publish(&self, buffer: &[u8]) {
+        match (self.mode, self.connected().as_slice()) {
+            (_, []) => self.primary().publish(buffer),
+            (MultipathMode::Redundant, connected) => connected
+                .iter()
+                .for_each(|publication| publication.publish(buffer)),
+            (MultipathMode::RoundRobin, connected) => {
+                let next = self.next.get();
+                self.next.set(next.wrapping_add(1));
+                connected[next % connected.len()].publish(buffer)
+            }
+            (MultipathMode::PrimaryBackup, [first, ..]) => first.publish(buffer),
+        }
+    }
+
+    /// Publishes on every connected path, so that the ones not selected for datagrams are known
+    /// alive by the server too
+    pub fn publish_all(&self, buffer: &[u8]) {
+        match self.connected().as_slice() {
+            [] => self.primary().publish(buffer),
+            connected => connected
+                .iter()
+                .for_each(|publication| publication.publish(buffer)),
+        }
+    }
+
+    pub fn poll(&self, on_fragment: &mut dyn FnMut(&[u8], &FragmentHeader)) -> usize {
+        self.subscriptions
+            .iter()
+            .map(|subscription| subscription.poll(on_fragment))
+            .sum()
+    }
+
+    pub fn close(&self) {
+        self.subscriptions
+            .iter()
+            .for_each(|subscription| subscription.close());
+        self.publications
+            .iter()
+            .for_each(|publication| publication.close());
+    }
+}
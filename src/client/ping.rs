@@ -0,0 +1,112 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::error;
+
+use crate::client::Client;
+use crate::latency::Pinger;
+use crate::messages::{Capabilities, Frame};
+use crate::transport::FragmentHeader;
+
+/// Least time given to the pong of the last ping to arrive
+const LAST_PONG_WAIT: Duration = Duration::from_secs(1);
+
+impl Client {
+    /// Measures the round trip time through a slot of the server the way the classic ping tool
+    /// does, printing each pong as it arrives and a summary at the end
+    pub fn ping(self, running: Arc<AtomicBool>) {
+        if self.args.ping_interval == 0 {
+            error!("The ping interval must be positive");
+            return;
+        }
+        let connection = match self.handshake(&running) {
+            Some(Ok(connection)) => connection,
+            Some(Err(failure)) => {
+                error!("Handshake failed: {:?}", failure);
+                return;
+            }
+            None => return,
+        };
+        if !connection.capabilities.contains(Capabilities::PING) {
+            error!("Server does not answer pings");
+            return;
+        }
+
+        let server = self.server();
+        let paths = self.open_paths(&connection, std::slice::from_ref(&self.args.interface));
+        let send = |frame: &Frame| paths.publish(&bincode::serialize(frame).unwrap());
+
+        let interval = Duration::from_millis(self.args.ping_interval);
+        let heartbeat_interval = connection
+            .capabilities
+            .contains(Capabilities::HEARTBEAT)
+            .then(|| Duration::from_millis(connection.heartbeat_interval));
+        let pinger = RefCell::new(Pinger::new(interval));
+        let mut on_new_fragment =
+            |buffer: &[u8], _: &FragmentHeader| match bincode::deserialize::<Frame>(buffer) {
+                Ok(Frame::Pong {
+                    sequence,
+                    timestamp,
+                }) => {
+                    let Some(rtt) = pinger.borrow_mut().pong(timestamp) else {
+                        return;
+                    };
+                    println!(
+                        "{} bytes from {}: seq={} time={:.3} ms",
+                        buffer.len(),
+                        server,
+                        sequence,
+                        rtt.as_secs_f64() * 1000.0
+                    );
+                }
+                Ok(Frame::Ping {
+                    sequence,
+                    timestamp,
+                }) => send(&Frame::Pong {
+                    sequence,
+                    timestamp,
+                }),
+                _ => {}
+            };
+
+        println!("PING {} through port {}", server, connection.port);
+        let count = self.args.ping_count;
+        let mut last_sent = Instant::now();
+        while running.load(Ordering::SeqCst) {
+            if count == 0 || pinger.borrow().transmitted() < count {
+                let ping = pinger.borrow_mut().ping();
+                if let Some(ping) = ping {
+                    send(&ping);
+                    last_sent = Instant::now();
+                }
+            } else if pinger.borrow().stats.len() >= count as usize
+                || last_sent.elapsed() >= interval.max(LAST_PONG_WAIT)
+            {
+                break;
+            }
+            // Pings further apart than heartbeats would let the server think this client dead
+            if heartbeat_interval.is_some_and(|heartbeat| last_sent.elapsed() >= heartbeat) {
+                send(&Frame::Heartbeat);
+                last_sent = Instant::now();
+            }
+            paths.poll(&mut on_new_fragment);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        paths.close();
+
+        let pinger = pinger.borrow();
+        let transmitted = pinger.transmitted();
+        let received = pinger.stats.len();
+        println!("--- {} ping statistics ---", server);
+        println!(
+            "{} transmitted, {} received, {:.1}% packet loss",
+            transmitted,
+            received,
+            100.0 * transmitted.saturating_sub(received as u32) as f64 / transmitted.max(1) as f64
+        );
+        println!("rtt {}", pinger.stats);
+    }
+}
@@ -0,0 +1,168 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the client picks the server it fails over to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerSelection {
+    /// The next one in the list, wrapping around
+    Ordered,
+    /// The one with the lowest handshake latency measured so far, the ones not measured yet
+    /// being tried first in list order
+    Latency,
+}
+
+impl FromStr for ServerSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ordered" => Ok(ServerSelection::Ordered),
+            "latency" => Ok(ServerSelection::Latency),
+            _ => Err(format!("Unknown server selection {}", s)),
+        }
+    }
+}
+
+/// Servers the client connects to, one at a time, failing over to another one when a
+/// handshake times out or is refused, or when the session is lost. A server may redirect the
+/// client to another one of its pool, which stands in for it until the next fail over.
+pub(super) struct Servers {
+    addresses: Vec<String>,
+    selection: ServerSelection,
+    latencies: Vec<Option<Duration>>,
+    active: usize,
+    failures: usize,
+    redirected: Option<String>,
+    redirects: usize,
+    max_redirects: usize,
+}
+
+impl Servers {
+    pub fn new(addresses: Vec<String>, selection: ServerSelection, max_redirects: usize) -> Self {
+        Servers {
+            latencies: vec![None; addresses.len()],
+            addresses,
+            selection,
+            active: 0,
+            failures: 0,
+            redirected: None,
+            redirects: 0,
+            max_redirects,
+        }
+    }
+
+    /// Server currently connected to, or being handshaken with
+    pub fn active(&self) -> &str {
+        self.redirected
+            .as_deref()
+            .unwrap_or(&self.addresses[self.active])
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    /// Whether there is another server to fail over to, be it a listed one or, after a
+    /// redirect, the listed one redirecting
+    pub fn can_fail_over(&self) -> bool {
+        self.len() > 1 || self.redirected.is_some()
+    }
+
+    /// Records the handshake latency of the active server, which accepted the client
+    pub fn connected(&mut self, latency: Duration) {
+        if self.redirected.is_none() {
+            self.latencies[self.active] = Some(latency);
+        }
+        self.failures = 0;
+        self.redirects = 0;
+    }
+
+    /// Follows a redirect to another server, returns false when too many were in a row
+    pub fn redirect(&mut self, server: String) -> bool {
+        if self.redirects >= self.max_redirects {
+            return false;
+        }
+        self.redirects += 1;
+        self.redirected = Some(server);
+        true
+    }
+
+    /// Moves on to another server, the same one when there is no other
+    pub fn fail_over(&mut self) -> &str {
+        self.failures += 1;
+        self.redirected = None;
+        self.redirects = 0;
+        let others = (1..self.len()).map(|offset| (self.active + offset) % self.len());
+        self.active = match self.selection {
+            ServerSelection::Ordered => others.take(1).next(),
+            ServerSelection::Latency => {
+                others.min_by_key(|index| self.latencies[*index].unwrap_or_default())
+            }
+        }
+        .unwrap_or(self.active);
+        self.active()
+    }
+
+    /// Whether every server failed in a row since one was last connected to
+    pub fn exhausted(&self) -> bool {
+        self.failures >= self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(selection: ServerSelection) -> Servers {
+        Servers::new(
+            vec![
+                String::from("192.0.2.1"),
+                String::from("192.0.2.2"),
+                String::from("192.0.2.3"),
+            ],
+            selection,
+            2,
+        )
+    }
+
+    #[test]
+    fn test_fail_over() {
+        let mut ordered = servers(ServerSelection::Ordered);
+        assert_eq!(ordered.active(), "192.0.2.1");
+        assert_eq!(ordered.fail_over(), "192.0.2.2");
+        assert_eq!(ordered.fail_over(), "192.0.2.3");
+        assert!(!ordered.exhausted());
+        assert_eq!(ordered.fail_over(), "192.0.2.1");
+        assert!(ordered.exhausted());
+        ordered.connected(Duration::from_millis(5));
+        assert!(!ordered.exhausted());
+
+        let mut latency = servers(ServerSelection::Latency);
+        latency.connected(Duration::from_millis(30));
+        assert_eq!(latency.fail_over(), "192.0.2.2");
+        latency.connected(Duration::from_millis(10));
+        assert_eq!(latency.fail_over(), "192.0.2.3");
+        latency.connected(Duration::from_millis(20));
+        // The fastest of the others once all are measured
+        assert_eq!(latency.fail_over(), "192.0.2.2");
+        assert_eq!(latency.fail_over(), "192.0.2.3");
+
+        let mut single = Servers::new(vec![String::from("192.0.2.1")], ServerSelection::Ordered, 0);
+        assert_eq!(single.fail_over(), "192.0.2.1");
+        assert!(!single.redirect(String::from("192.0.2.9")));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let mut servers = servers(ServerSelection::Ordered);
+        assert!(servers.redirect(String::from("192.0.2.8")));
+        assert!(servers.redirect(String::from("192.0.2.9")));
+        assert_eq!(servers.active(), "192.0.2.9");
+        assert!(!servers.redirect(String::from("192.0.2.10")));
+        servers.connected(Duration::from_millis(5));
+        assert!(servers.redirect(String::from("192.0.2.10")));
+        // Redirects stand in for the listed server until the next fail over
+        assert_eq!(servers.fail_over(), "192.0.2.2");
+        assert!(servers.redirect(String::from("192.0.2.8")));
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::messages::{Compression, Datagram};
+
+const ZSTD_LEVEL: i32 = 3;
+const MAX_DECOMPRESSED_LENGTH: usize = 65535;
+
+#[derive(Default)]
+pub struct CompressionStats {
+    packets: AtomicU64,
+    compressed_packets: AtomicU64,
+    original_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    fn record(&self, original_length: usize, sent_length: usize, compressed: bool) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        if compressed {
+            self.compressed_packets.fetch_add(1, Ordering::Relaxed);
+        }
+        self.original_bytes
+            .fetch_add(original_length as u64, Ordering::Relaxed);
+        self.sent_bytes
+            .fetch_add(sent_length as u64, Ordering::Relaxed);
+    }
+
+    pub fn ratio(&self) -> f64 {
+        let original_bytes = self.original_bytes.load(Ordering::Relaxed);
+        if original_bytes == 0 {
+            1.0
+        } else {
+            self.sent_bytes.load(Ordering::Relaxed) as f64 / original_bytes as f64
+        }
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} packets ({} compressed), {} bytes sent as {} (ratio {:.2})",
+            self.packets.load(Ordering::Relaxed),
+            self.compressed_packets.load(Ordering::Relaxed),
+            self.original_bytes.load(Ordering::Relaxed),
+            self.sent_bytes.load(Ordering::Relaxed),
+            self.ratio()
+        )
+    }
+}
+
+/// Compresses the payload of the datagrams sent through the tunnel with the algorithm negotiated
+/// during the handshake. Payloads shorter than the threshold, or that don't shrink, are sent as they are.
+#[derive(Clone)]
+pub struct Compressor {
+    compression: Compression,
+    threshold: usize,
+    stats: Arc<CompressionStats>,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression, threshold: usize) -> Self {
+        Compressor {
+            compression,
+            threshold,
+            stats: Arc::new(CompressionStats::default()),
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn stats(&self) -> &CompressionStats {
+        &self.stats
+    }
+
+    pub fn compress(&self, datagram: &Datagram) -> Datagram {
+        let original_length = datagram.payload.len();
+        let compressed = if original_length < self.threshold {
+            None
+        } else {
+            match self.compression {
+                Compression::None => None,
+                Compression::Lz4 => Some(lz4_flex::compress_prepend_size(&datagram.payload)),
+                Compression::Zstd => zstd::bulk::compress(&datagram.payload, ZSTD_LEVEL).ok(),
+            }
+        };
+        match compressed {
+            Some(payload) if payload.len() < original_length => {
+                self.stats.record(original_length, payload.len(), true);
+                Datagram {
+                    metadata: datagram.metadata,
+                    compression: self.compression,
+                    payload,
+                }
+            }
+            _ => {
+                self.stats.record(original_length, original_length, false);
+                datagram.clone()
+            }
+        }
+    }
+}
+
+pub fn decompress(datagram: Datagram) -> io::Result<Datagram> {
+    let payload = match datagram.compression {
+        Compression::None => return Ok(datagram),
+        Compression::Lz4 => {
+            let length = datagram
+                .payload
+                .get(..4)
+                .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize)
+                .unwrap_or(usize::MAX);
+            if length > MAX_DECOMPRESSED_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Decompressed length {} exceeds limit", length),
+                ));
+            }
+            lz4_flex::decompress_size_prepended(&datagram.payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        }
+        Compression::Zstd => zstd::bulk::decompress(&datagram.payload, MAX_DECOMPRESSED_LENGTH)?,
+    };
+    Ok(Datagram {
+        metadata: datagram.metadata,
+        compression: Compression::None,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::Origin;
+
+    fn datagram(payload: &[u8]) -> Datagram {
+        Datagram::new(
+            Origin::Client(1),
+            "127.0.0.1:19900".parse().unwrap(),
+            payload,
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let original = datagram(&[42u8; 512]);
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let compressor = Compressor::new(compression, 64);
+            let compressed = compressor.compress(&original);
+            assert_eq!(compressed.compression, compression);
+            assert!(compressed.payload.len() < original.payload.len());
+            assert_eq!(decompress(compressed).unwrap(), original);
+            assert!(compressor.stats().ratio() < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_small_packet_bypass() {
+        let original = datagram(&[42u8; 32]);
+        let compressor = Compressor::new(Compression::Zstd, 64);
+        let compressed = compressor.compress(&original);
+        assert_eq!(compressed, original);
+        assert_eq!(compressor.stats().ratio(), 1.0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::messages::{Capabilities, Datagram, Frame};
+
+/// Microseconds since the Unix epoch, comparable across hosts whose clocks are synchronized
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Deadlines of the datagrams exchanged with the other side. Datagrams sent carry one when a
+/// maximum age is set and the other side understands them, and the ones received past theirs
+/// are counted as stale, to be dropped instead of delivered late.
+#[derive(Clone, Default)]
+pub struct Deadline {
+    max_age: Option<Duration>,
+    stale: Arc<AtomicU64>,
+}
+
+impl Deadline {
+    pub fn new(max_age: u64, capabilities: Capabilities) -> Self {
+        Deadline {
+            max_age: (max_age > 0 && capabilities.contains(Capabilities::DEADLINE))
+                .then(|| Duration::from_millis(max_age)),
+            stale: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Deadline of a datagram sent now
+    pub fn deadline(&self) -> Option<u64> {
+        self.max_age
+            .map(|max_age| now() + max_age.as_micros() as u64)
+    }
+
+    /// Frame of a datagram sent now
+    pub fn frame(&self, datagram: Datagram) -> Frame {
+        match self.deadline() {
+            Some(deadline) => Frame::Expiring { deadline, datagram },
+            None => Frame::Datagram(datagram),
+        }
+    }
+
+    /// Whether a datagram received with this deadline is stale, counting it if so
+    pub fn is_stale(&self, deadline: u64) -> bool {
+        let stale = now() > deadline;
+        if stale {
+            self.stale.fetch_add(1, Ordering::Relaxed);
+        }
+        stale
+    }
+
+    /// Datagrams received stale so far
+    pub fn stale(&self) -> u64 {
+        self.stale.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::Origin;
+
+    #[test]
+    fn test_deadline() {
+        let datagram = Datagram::new(
+            Origin::Client(1),
+            "127.0.0.1:19900".parse().unwrap(),
+            b"state",
+        );
+        let deadline = Deadline::new(0, Capabilities::SUPPORTED);
+        assert!(matches!(
+            deadline.frame(datagram.clone()),
+            Frame::Datagram(_)
+        ));
+        let deadline = Deadline::new(500, Capabilities::default());
+        assert!(matches!(
+            deadline.frame(datagram.clone()),
+            Frame::Datagram(_)
+        ));
+
+        let deadline = Deadline::new(500, Capabilities::SUPPORTED);
+        let Frame::Expiring { deadline: at, .. } = deadline.frame(datagram) else {
+            panic!("Datagram sent without deadline");
+        };
+        assert!(!deadline.is_stale(at));
+        assert!(deadline.is_stale(at - 1_000_000));
+        assert_eq!(deadline.stale(), 1);
+    }
+}
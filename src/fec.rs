@@ -0,0 +1,529 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::error;
+
+use crate::messages::{Capabilities, Frame};
+
+/// Groups waiting for missing shards behind the newest one, older ones being given up
+const GROUPS_KEPT: u64 = 16;
+
+/// Time after which a partially filled group is closed with the data shards it has, so that
+/// its parity is not held back when traffic pauses
+const FLUSH_DELAY: Duration = Duration::from_millis(20);
+
+/// Arithmetic in GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1
+struct Galois {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Galois {
+    const fn new() -> Self {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+            i += 1;
+        }
+        Galois { exp, log }
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+        }
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+}
+
+const GF: Galois = Galois::new();
+
+/// Row of the systematic encoding matrix generating a shard: the identity for data shards, a
+/// Cauchy row for parity ones, any square selection of rows being invertible
+fn row(index: usize, data: usize) -> Vec<u8> {
+    (0..data)
+        .map(|column| match index < data {
+            true => (column == index) as u8,
+            false => GF.inv(index as u8 ^ column as u8),
+        })
+        .collect()
+}
+
+/// Inverse of a square matrix by Gauss-Jordan elimination
+fn invert(mut matrix: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n).map(|i| row(i, n)).collect();
+    for column in 0..n {
+        let pivot = (column..n)
+            .find(|&r| matrix[r][column] != 0)
+            .expect("Singular matrix");
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+        let factor = GF.inv(matrix[column][column]);
+        matrix[column]
+            .iter_mut()
+            .for_each(|x| *x = GF.mul(*x, factor));
+        inverse[column]
+            .iter_mut()
+            .for_each(|x| *x = GF.mul(*x, factor));
+        for r in (0..n).filter(|&r| r != column) {
+            let factor = matrix[r][column];
+            if factor == 0 {
+                continue;
+            }
+            for c in 0..n {
+                matrix[r][c] ^= GF.mul(factor, matrix[column][c]);
+                inverse[r][c] ^= GF.mul(factor, inverse[column][c]);
+            }
+        }
+    }
+    inverse
+}
+
+/// Linear combination of shards, padded with zeros to the longest
+fn combine(coefficients: &[u8], shards: &[&Vec<u8>]) -> Vec<u8> {
+    let length = shards.iter().map(|shard| shard.len()).max().unwrap_or(0);
+    let mut result = vec![0u8; length];
+    coefficients
+        .iter()
+        .zip(shards)
+        .filter(|(coefficient, _)| **coefficient != 0)
+        .for_each(|(coefficient, shard)| {
+            result
+                .iter_mut()
+                .zip(shard.iter())
+                .for_each(|(x, y)| *x ^= GF.mul(*coefficient, *y))
+        });
+    result
+}
+
+/// Shard content of a serialized frame, prefixed with its length to strip the padding once
+/// recovered
+fn prefixed(frame: &[u8]) -> Vec<u8> {
+    let mut shard = (frame.len() as u32).to_le_bytes().to_vec();
+    shard.extend_from_slice(frame);
+    shard
+}
+
+fn unprefixed(shard: &[u8]) -> Option<&[u8]> {
+    let length = u32::from_le_bytes(shard.get(..4)?.try_into().ok()?) as usize;
+    shard.get(4..4 + length)
+}
+
+/// Data and parity shards per group, as DATA:PARITY, none disabling error correction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Redundancy {
+    pub data: usize,
+    pub parity: usize,
+}
+
+impl FromStr for Redundancy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (data, parity) = s.split_once(':').unwrap_or((s, "0"));
+        let parse = |value: &str| {
+            value
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid redundancy {}: {}", s, e))
+        };
+        let redundancy = Redundancy {
+            data: parse(data)?,
+            parity: parse(parity)?,
+        };
+        if redundancy.data + redundancy.parity > 255 {
+            return Err(format!("Redundancy {} exceeds 255 shards per group", s));
+        }
+        Ok(redundancy)
+    }
+}
+
+impl Redundancy {
+    pub fn is_enabled(&self) -> bool {
+        self.data > 0 && self.parity > 0
+    }
+}
+
+/// Groups the frames sent into data shards, followed by the parity shards of each group
+struct Encoder {
+    redundancy: Redundancy,
+    group: u64,
+    shards: Vec<Vec<u8>>,
+    started: Instant,
+}
+
+impl Encoder {
+    fn encode(&mut self, frame: &Frame) -> Vec<Frame> {
+        let serialized = bincode::serialize(frame).unwrap();
+        if self.shards.is_empty() {
+            self.started = Instant::now();
+        }
+        self.shards.push(prefixed(&serialized));
+        let mut frames = vec![Frame::FecData {
+            group: self.group,
+            index: (self.shards.len() - 1) as u8,
+            frame: serialized,
+        }];
+        if self.shards.len() == self.redundancy.data {
+            frames.extend(self.close());
+        }
+        frames
+    }
+
+    fn flush(&mut self) -> Vec<Frame> {
+        if !self.shards.is_empty() && self.started.elapsed() >= FLUSH_DELAY {
+            self.close()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Parity shards of the current group, starting the next one
+    fn close(&mut self) -> Vec<Frame> {
+        let data = self.shards.len();
+        let shards: Vec<&Vec<u8>> = self.shards.iter().collect();
+        let frames = (data..data + self.redundancy.parity)
+            .map(|index| Frame::FecParity {
+                group: self.group,
+                index: index as u8,
+                data: data as u8,
+                parity: combine(&row(index, data), &shards),
+            })
+            .collect();
+        self.group += 1;
+        self.shards.clear();
+        frames
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    /// Number of data shards, known once a parity shard is received
+    data: Option<usize>,
+    shards: BTreeMap<usize, Vec<u8>>,
+    delivered: BTreeSet<usize>,
+}
+
+impl Group {
+    /// Frames of the missing data shards, once enough shards are received to recover them
+    fn recover(&mut self) -> Vec<Vec<u8>> {
+        let Some(data) = self.data else {
+            return vec![];
+        };
+        let missing: Vec<usize> = (0..data)
+            .filter(|index| !self.delivered.contains(index))
+            .collect();
+        if missing.is_empty() || self.shards.len() < data {
+            return vec![];
+        }
+        let (indexes, shards): (Vec<usize>, Vec<&Vec<u8>>) =
+            self.shards.iter().take(data).map(|(i, s)| (*i, s)).unzip();
+        let decoding = invert(indexes.iter().map(|index| row(*index, data)).collect());
+        let recovered = missing
+            .iter()
+            .map(|index| combine(&decoding[*index], &shards))
+            .collect();
+        self.delivered.extend(missing);
+        recovered
+    }
+
+    /// Data shards neither received nor recovered
+    fn lost(&self) -> u64 {
+        let data = self
+            .data
+            .or(self.shards.keys().next_back().map(|index| index + 1))
+            .unwrap_or(0);
+        data.saturating_sub(self.delivered.len()) as u64
+    }
+}
+
+/// Delivers the frames of the data shards received, and recovers the missing ones from the
+/// parity shards
+#[derive(Default)]
+struct Decoder {
+    groups: BTreeMap<u64, Group>,
+    recovered: u64,
+    unrecoverable: u64,
+}
+
+impl Decoder {
+    fn decode(&mut self, frame: Frame) -> Vec<Frame> {
+        let (group, serialized) = match frame {
+            Frame::FecData {
+                group,
+                index,
+                frame,
+            } => {
+                let index = index as usize;
+                let entry = self.groups.entry(group).or_default();
+                entry
+                    .shards
+                    .entry(index)
+                    .or_insert_with(|| prefixed(&frame));
+                let fresh = entry.delivered.insert(index);
+                let recovered = entry.recover();
+                self.recovered += recovered.len() as u64;
+                let mut serialized: Vec<Vec<u8>> = vec![];
+                if fresh {
+                    serialized.push(frame);
+                }
+                serialized.extend(
+                    recovered
+                        .iter()
+                        .filter_map(|shard| unprefixed(shard).map(|frame| frame.to_vec())),
+                );
+                (group, serialized)
+            }
+            Frame::FecParity {
+                group,
+                index,
+                data,
+                parity,
+            } => {
+                let entry = self.groups.entry(group).or_default();
+                entry.data = Some(data as usize);
+                entry.shards.entry(index as usize).or_insert(parity);
+                let recovered = entry.recover();
+                self.recovered += recovered.len() as u64;
+                let serialized = recovered
+                    .iter()
+                    .filter_map(|shard| unprefixed(shard).map(|frame| frame.to_vec()))
+                    .collect();
+                (group, serialized)
+            }
+            frame => return vec![frame],
+        };
+        self.expire(group);
+        serialized
+            .iter()
+            .filter_map(|frame| {
+                bincode::deserialize(frame)
+                    .map_err(|e| error!("Discarding malformed shard: {}", e))
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Gives up the groups too far behind the newest one, whose number comes off the wire and
+    /// may be anything
+    fn expire(&mut self, newest: u64) {
+        while let Some(entry) = self
+            .groups
+            .first_entry()
+            .filter(|entry| *entry.key() < newest.saturating_sub(GROUPS_KEPT - 1))
+        {
+            self.unrecoverable += entry.remove().lost();
+        }
+    }
+}
+
+/// Forward error correction of the datagrams exchanged with the other side. Datagrams sent are
+/// grouped and followed by parity shards when a redundancy is set and the other side understands
+/// them, while the lost datagrams received are recovered from the parity shards of their group
+/// without waiting for a retransmission.
+pub struct Fec {
+    encoder: Option<Mutex<Encoder>>,
+    decoder: Mutex<Decoder>,
+}
+
+impl Fec {
+    pub fn new(redundancy: Redundancy, capabilities: Capabilities) -> Self {
+        Fec {
+            encoder: (redundancy.is_enabled() && capabilities.contains(Capabilities::FEC)).then(
+                || {
+                    Mutex::new(Encoder {
+                        redundancy,
+                        group: 0,
+                        shards: vec![],
+                        started: Instant::now(),
+                    })
+                },
+            ),
+            decoder: Mutex::new(Decoder::default()),
+        }
+    }
+
+    /// Frames to send for a frame carrying a datagram
+    pub fn encode(&self, frame: Frame) -> Vec<Frame> {
+        match &self.encoder {
+            Some(encoder) => encoder.lock().unwrap().encode(&frame),
+            None => vec![frame],
+        }
+    }
+
+    /// Parity shards of a group left partially filled for a while
+    pub fn flush(&self) -> Vec<Frame> {
+        match &self.encoder {
+            Some(encoder) => encoder.lock().unwrap().flush(),
+            None => vec![],
+        }
+    }
+
+    /// Frames carried by a frame received: the one of a data shard received for the first time,
+    /// along with those recovered thanks to it
+    pub fn decode(&self, frame: Frame) -> Vec<Frame> {
+        self.decoder.lock().unwrap().decode(frame)
+    }
+
+    /// Whether any datagram was recovered or lost for good
+    pub fn has_losses(&self) -> bool {
+        let decoder = self.decoder.lock().unwrap();
+        decoder.recovered > 0 || decoder.unrecoverable > 0
+    }
+}
+
+impl fmt::Display for Fec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decoder = self.decoder.lock().unwrap();
+        write!(
+            f,
+            "{} datagrams recovered, {} unrecoverable",
+            decoder.recovered, decoder.unrecoverable
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{Datagram, Origin};
+
+    fn frames(count: usize) -> Vec<Frame> {
+        (0..count)
+            .map(|i| {
+                let payload = vec![i as u8; 10 + 7 * i];
+                Frame::Datagram(Datagram::new(
+                    Origin::Client(1),
+                    "127.0.0.1:19900".parse().unwrap(),
+                    &payload,
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_galois() {
+        (1..=255u8).for_each(|a| assert_eq!(GF.mul(a, GF.inv(a)), 1));
+        assert_eq!(GF.mul(0x53, 0xca), GF.mul(0xca, 0x53));
+    }
+
+    #[test]
+    fn test_recovery() {
+        let redundancy: Redundancy = "4:2".parse().unwrap();
+        let sender = Fec::new(redundancy, Capabilities::SUPPORTED);
+        let receiver = Fec::new(Redundancy::default(), Capabilities::SUPPORTED);
+        let originals = frames(8);
+        let shards: Vec<Frame> = originals
+            .iter()
+            .flat_map(|frame| sender.encode(frame.clone()))
+            .collect();
+        assert_eq!(shards.len(), 12);
+
+        // Two data shards of the first group lost, three of the second one
+        let received: Vec<Frame> = shards
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| ![1, 2, 6, 7, 8].contains(i))
+            .flat_map(|(_, shard)| receiver.decode(shard))
+            .collect();
+        // Recovered once the parity shards arrive, after the data shards that did
+        assert_eq!(
+            received,
+            [0, 3, 1, 2, 7]
+                .iter()
+                .map(|i| originals[*i].clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            receiver.to_string(),
+            "2 datagrams recovered, 0 unrecoverable"
+        );
+
+        // Given up once far enough behind
+        (0..GROUPS_KEPT * 4).for_each(|_| {
+            sender.encode(originals[0].clone());
+        });
+        receiver.decode(sender.encode(originals[0].clone()).remove(0));
+        assert_eq!(
+            receiver.to_string(),
+            "2 datagrams recovered, 3 unrecoverable"
+        );
+    }
+
+    #[test]
+    fn test_groups_near_max() {
+        let mut decoder = Decoder::default();
+        let shard = |group| Frame::FecData {
+            group,
+            index: 0,
+            frame: bincode::serialize(&frames(1)[0]).unwrap(),
+        };
+        let behind = u64::MAX - GROUPS_KEPT;
+        [behind, u64::MAX - 1, u64::MAX]
+            .into_iter()
+            .for_each(|group| {
+                assert_eq!(decoder.decode(shard(group)), frames(1));
+            });
+        // Only the group as far behind as kept is given up, the others stay buffered
+        assert_eq!(
+            decoder.groups.keys().copied().collect::<Vec<u64>>(),
+            vec![u64::MAX - 1, u64::MAX]
+        );
+        decoder.decode(shard(0));
+        assert_eq!(decoder.groups.len(), 3);
+    }
+
+    #[test]
+    fn test_flush() {
+        let sender = Fec::new("10:1".parse().unwrap(), Capabilities::SUPPORTED);
+        let receiver = Fec::new(Redundancy::default(), Capabilities::SUPPORTED);
+        let originals = frames(3);
+        let mut shards: Vec<Frame> = originals
+            .iter()
+            .flat_map(|frame| sender.encode(frame.clone()))
+            .collect();
+        assert!(sender.flush().is_empty());
+        std::thread::sleep(FLUSH_DELAY);
+        shards.extend(sender.flush());
+        assert_eq!(shards.len(), 4);
+        shards.remove(1);
+        let received: Vec<Frame> = shards
+            .into_iter()
+            .flat_map(|shard| receiver.decode(shard))
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                originals[0].clone(),
+                originals[2].clone(),
+                originals[1].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let fec = Fec::new("4:2".parse().unwrap(), Capabilities::default());
+        assert_eq!(fec.encode(frames(1).remove(0)), frames(1));
+        assert_eq!(fec.decode(frames(1).remove(0)), frames(1));
+        assert!("200:100".parse::<Redundancy>().is_err());
+    }
+}
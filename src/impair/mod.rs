@@ -0,0 +1,243 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::Add;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+use tracing::{debug, error, info};
+
+use crate::{Arguments, Timeout};
+
+/// Delay added to the datagrams picked for reordering, so that the following ones overtake them
+const REORDER_DELAY: Duration = Duration::from_millis(10);
+
+/// Network conditions applied to each datagram crossing the proxy, in either direction
+#[derive(Clone, Copy, Debug)]
+struct Impairment {
+    loss: f64,
+    duplicate: f64,
+    reorder: f64,
+    delay: Duration,
+    jitter: Duration,
+}
+
+#[derive(Default)]
+struct Statistics {
+    forwarded: usize,
+    dropped: usize,
+    duplicated: usize,
+    reordered: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Route {
+    /// From the client to the server, through the upstream socket of the client
+    Upstream(usize, SocketAddr),
+    /// From the server back to the client, through the front socket
+    Downstream(usize, SocketAddr),
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Scheduled {
+    due: Instant,
+    sequence: u64,
+    route: Route,
+    payload: Vec<u8>,
+}
+
+/// UDP proxy placed between clients and server, relaying the handshake and client slot ports
+/// of the server from the same ports shifted by an offset while degrading the network
+/// conditions, to verify how the tunnel copes with them.
+pub struct Impair {
+    fronts: Vec<UdpSocket>,
+    upstreams: Vec<SocketAddr>,
+    impairment: Impairment,
+}
+
+impl Impair {
+    pub fn instance(args: &Arguments) -> Self {
+        let ports = [args.port, args.control]
+            .into_iter()
+            .chain(args.port_range.clone())
+            .chain(args.control_range.clone())
+            .collect::<Vec<usize>>();
+        let upstreams = ports
+            .iter()
+            .map(|port| {
+                (args.server.as_str(), *port as u16)
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .expect("Cannot resolve server address")
+            })
+            .collect();
+        let fronts = ports
+            .iter()
+            .map(|port| {
+                let socket = UdpSocket::bind(("0.0.0.0", (port + args.port_offset) as u16))
+                    .unwrap_or_else(|e| {
+                        panic!("Error binding port {}: {}", port + args.port_offset, e)
+                    });
+                socket
+                    .set_nonblocking(true)
+                    .expect("Failed to enter non-blocking mode");
+                socket
+            })
+            .collect();
+
+        Impair {
+            fronts,
+            upstreams,
+            impairment: Impairment {
+                loss: args.impair_loss,
+                duplicate: args.impair_duplicate,
+                reorder: args.impair_reorder,
+                delay: Duration::from_millis(args.impair_delay),
+                jitter: Duration::from_millis(args.impair_jitter),
+            },
+        }
+    }
+
+    pub fn start(self, running: Arc<AtomicBool>) {
+        let mut upstream_sockets: HashMap<(usize, SocketAddr), UdpSocket> = HashMap::new();
+        let mut queue: BinaryHeap<Reverse<Scheduled>> = BinaryHeap::new();
+        let mut statistics = Statistics::default();
+        let mut sequence = 0;
+        let mut rng = rand::thread_rng();
+
+        info!(
+            "Relaying {} ports to {} with {:?}",
+            self.fronts.len(),
+            self.upstreams[0].ip(),
+            self.impairment
+        );
+
+        let mut statistics_timeout =
+            SystemTime::now().add(Duration::from_secs(Timeout::STATISTICS_SECONDS));
+        let mut recv_buff = vec![0; 65535];
+        while running.load(Ordering::SeqCst) {
+            if statistics_timeout < SystemTime::now() {
+                statistics_timeout =
+                    statistics_timeout.add(Duration::from_secs(Timeout::STATISTICS_SECONDS));
+                statistics.log();
+            }
+
+            let mut received = vec![];
+            for (index, front) in self.fronts.iter().enumerate() {
+                while let Some((n, addr)) = receive(front, &mut recv_buff) {
+                    received.push((Route::Upstream(index, addr), recv_buff[..n].to_vec()));
+                }
+            }
+            for ((index, client), socket) in upstream_sockets.iter() {
+                while let Some((n, _)) = receive(socket, &mut recv_buff) {
+                    received.push((Route::Downstream(*index, *client), recv_buff[..n].to_vec()));
+                }
+            }
+
+            for (route, payload) in received {
+                if let Route::Upstream(index, client) = route {
+                    upstream_sockets.entry((index, client)).or_insert_with(|| {
+                        debug!("Relaying {} to upstream {}", client, self.upstreams[index]);
+                        let socket = UdpSocket::bind("0.0.0.0:0").expect("Error binding socket");
+                        socket
+                            .connect(self.upstreams[index])
+                            .expect("Error connecting upstream socket");
+                        socket
+                            .set_nonblocking(true)
+                            .expect("Failed to enter non-blocking mode");
+                        socket
+                    });
+                }
+                self.impairment
+                    .schedule(&mut rng, &mut statistics)
+                    .into_iter()
+                    .for_each(|due| {
+                        sequence += 1;
+                        queue.push(Reverse(Scheduled {
+                            due,
+                            sequence,
+                            route,
+                            payload: payload.clone(),
+                        }));
+                    });
+            }
+
+            let now = Instant::now();
+            while queue.peek().is_some_and(|Reverse(next)| next.due <= now) {
+                let Reverse(scheduled) = queue.pop().unwrap();
+                let result = match scheduled.route {
+                    Route::Upstream(index, client) => {
+                        upstream_sockets[&(index, client)].send(&scheduled.payload)
+                    }
+                    Route::Downstream(index, client) => {
+                        self.fronts[index].send_to(&scheduled.payload, client)
+                    }
+                };
+                match result {
+                    Ok(_) => statistics.forwarded += 1,
+                    Err(e) => error!("Can't relay datagram: {}", e),
+                }
+            }
+
+            std::thread::sleep(Duration::from_micros(200));
+        }
+        statistics.log();
+    }
+}
+
+impl Impairment {
+    /// The instants when the copies of a datagram received now are due, none if it is lost
+    fn schedule(&self, rng: &mut impl Rng, statistics: &mut Statistics) -> Vec<Instant> {
+        if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+            statistics.dropped += 1;
+            return vec![];
+        }
+        let copies = if rng.gen_bool(self.duplicate.clamp(0.0, 1.0)) {
+            statistics.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut delay = self.delay;
+                if !self.jitter.is_zero() {
+                    let jitter = rng.gen_range(0.0..=2.0 * self.jitter.as_secs_f64());
+                    delay = Duration::from_secs_f64(
+                        (delay.as_secs_f64() + jitter - self.jitter.as_secs_f64()).max(0.0),
+                    );
+                }
+                if rng.gen_bool(self.reorder.clamp(0.0, 1.0)) {
+                    statistics.reordered += 1;
+                    delay += REORDER_DELAY;
+                }
+                Instant::now() + delay
+            })
+            .collect()
+    }
+}
+
+impl Statistics {
+    fn log(&self) {
+        info!(
+            "Relayed {} datagrams: {} dropped, {} duplicated, {} reordered",
+            self.forwarded, self.dropped, self.duplicated, self.reordered
+        );
+    }
+}
+
+fn receive(socket: &UdpSocket, buffer: &mut [u8]) -> Option<(usize, SocketAddr)> {
+    match socket.recv_from(buffer) {
+        Ok(received) => Some(received),
+        Err(err) => {
+            if err.kind() != io::ErrorKind::WouldBlock {
+                error!("Error receiving datagram to relay: {:?}", err)
+            }
+            None
+        }
+    }
+}
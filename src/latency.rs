@@ -0,0 +1,165 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::messages::Frame;
+
+/// Round trip times measured over a period
+#[derive(Clone, Debug, Default)]
+pub struct LatencyStats {
+    samples: Vec<Duration>,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, rtt: Duration) {
+        self.samples.push(rtt);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Nearest-rank percentile of the samples
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied()
+    }
+
+    fn min(&self) -> Option<Duration> {
+        self.samples.iter().min().copied()
+    }
+
+    fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    fn avg(&self) -> Option<Duration> {
+        (!self.is_empty()).then(|| self.samples.iter().sum::<Duration>() / self.len() as u32)
+    }
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |duration: Option<Duration>| {
+            duration
+                .map(|duration| format!("{:.3}", duration.as_secs_f64() * 1000.0))
+                .unwrap_or(String::from("-"))
+        };
+        write!(
+            f,
+            "min/avg/max/p50/p90/p99 = {}/{}/{}/{}/{}/{} ms over {} samples",
+            millis(self.min()),
+            millis(self.avg()),
+            millis(self.max()),
+            millis(self.percentile(50.0)),
+            millis(self.percentile(90.0)),
+            millis(self.percentile(99.0)),
+            self.len()
+        )
+    }
+}
+
+/// Sends pings at a regular interval and measures the round trip time of the pongs, whose
+/// timestamps are the ones of the pings they answer
+pub struct Pinger {
+    epoch: Instant,
+    interval: Duration,
+    next: Instant,
+    sequence: u32,
+    answered: Option<u64>,
+    pub stats: LatencyStats,
+}
+
+impl Pinger {
+    pub fn new(interval: Duration) -> Self {
+        Pinger {
+            epoch: Instant::now(),
+            interval,
+            next: Instant::now(),
+            sequence: 0,
+            answered: None,
+            stats: LatencyStats::default(),
+        }
+    }
+
+    /// Pings sent so far
+    pub fn transmitted(&self) -> u32 {
+        self.sequence
+    }
+
+    /// The next ping, when it is due
+    pub fn ping(&mut self) -> Option<Frame> {
+        let now = Instant::now();
+        if now < self.next {
+            return None;
+        }
+        self.next = now + self.interval;
+        self.sequence += 1;
+        Some(Frame::Ping {
+            sequence: self.sequence,
+            timestamp: now.duration_since(self.epoch).as_micros() as u64,
+        })
+    }
+
+    /// Records the round trip time of a pong, none for the copies of pongs already answered, which
+    /// arrive when the other side sends over several paths
+    pub fn pong(&mut self, timestamp: u64) -> Option<Duration> {
+        if self.answered.is_some_and(|answered| timestamp <= answered) {
+            return None;
+        }
+        self.answered = Some(timestamp);
+        let rtt = self
+            .epoch
+            .elapsed()
+            .saturating_sub(Duration::from_micros(timestamp));
+        self.stats.record(rtt);
+        Some(rtt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let mut stats = LatencyStats::default();
+        assert_eq!(stats.percentile(50.0), None);
+        (1..=100).for_each(|millis| stats.record(Duration::from_millis(millis)));
+        assert_eq!(stats.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(stats.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(stats.percentile(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(stats.avg(), Some(Duration::from_micros(50500)));
+        assert!(stats
+            .to_string()
+            .starts_with("min/avg/max/p50/p90/p99 = 1.000/50.500/100.000/"));
+    }
+
+    #[test]
+    fn test_pinger() {
+        let mut pinger = Pinger::new(Duration::from_secs(60));
+        let Some(Frame::Ping {
+            sequence,
+            timestamp,
+        }) = pinger.ping()
+        else {
+            panic!("Ping not due");
+        };
+        assert_eq!(sequence, 1);
+        assert!(pinger.ping().is_none());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(pinger.pong(timestamp).unwrap() >= Duration::from_millis(5));
+        assert_eq!(pinger.pong(timestamp), None);
+        assert_eq!(pinger.stats.len(), 1);
+        assert_eq!(pinger.transmitted(), 1);
+    }
+}
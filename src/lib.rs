@@ -4,21 +4,43 @@ extern crate lazy_static;
 use std::env::temp_dir;
 use std::fs::File;
 use std::io::Write;
+use std::ops::RangeInclusive;
 use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use log::info;
+use tracing::{error, info};
 
-use crate::client::Client;
-use crate::server::Server;
-use std::net::UdpSocket;
+use crate::accounting::{Quota, QuotaAction};
+use crate::client::{Client, ServerSelection};
+use crate::fec::Redundancy;
+use crate::impair::Impair;
+pub use crate::logging::{init_logging, LogFormat};
+use crate::messages::{proxy, Compression, Datagram, Reliability};
+use crate::multipath::MultipathMode;
+use crate::rate_limit::{LimitAction, RateLimit};
+use crate::replay::{Replay, ReplayFilter};
+use crate::server::{Server, SlotPolicy};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 
+mod accounting;
 mod aeron;
+mod capture;
 mod client;
+mod compression;
+mod deadline;
+mod fec;
+mod impair;
+mod latency;
+mod logging;
 mod messages;
+mod multipath;
+mod rate_limit;
+mod replay;
 mod server;
+mod systemd;
+mod transport;
 
 lazy_static! {
     static ref RUNNING: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
@@ -27,39 +49,97 @@ lazy_static! {
 pub struct Timeout {}
 impl Timeout {
     pub const HANDSHAKE_RETRY_SECONDS: u64 = 30;
+    pub const HANDSHAKE_SECONDS: u64 = 10;
     pub const CONNECTION_SECONDS: u64 = 90;
     pub const SESSION_SECONDS: u64 = 600;
+    pub const STATISTICS_SECONDS: u64 = 60;
 }
 
 pub enum Mode {
     Client,
     Server,
+    Replay,
+    Impair,
+    Ping,
 }
 
 #[derive(Clone, Debug)]
 pub struct Arguments {
     pub port: usize,
     pub control: usize,
+    pub single_port: bool,
+    pub ipc: bool,
+    pub port_range: RangeInclusive<usize>,
+    pub control_range: RangeInclusive<usize>,
     pub server: String,
+    pub servers: Vec<String>,
+    pub server_selection: ServerSelection,
+    pub max_redirects: usize,
+    pub redirect: Vec<IpAddr>,
+    pub drain_file: Option<String>,
     pub public: String,
     pub interface: String,
+    pub interfaces: Vec<String>,
+    pub multipath: MultipathMode,
     pub sforward: String,
     pub sbackward: String,
     pub cforward: String,
     pub cbackward: String,
     pub listen: bool,
     pub endpoint: String,
+    pub accept: Vec<IpAddr>,
+    pub allow: Option<String>,
+    pub deny: Option<String>,
+    pub proxy_protocol: bool,
+    pub compression: Vec<Compression>,
+    pub compression_threshold: usize,
+    pub reliability: Reliability,
+    pub max_age: u64,
+    pub fec: Redundancy,
+    pub capture: Option<String>,
+    pub limit_up: RateLimit,
+    pub limit_down: RateLimit,
+    pub global_limit_up: RateLimit,
+    pub global_limit_down: RateLimit,
+    pub limit_action: LimitAction,
+    pub identity: String,
+    pub identities: Option<String>,
+    pub accounting: Option<String>,
+    pub daily_quota: Quota,
+    pub monthly_quota: Quota,
+    pub quota_action: QuotaAction,
+    pub quota_throttle: RateLimit,
+    pub heartbeat_interval: u64,
+    pub heartbeat_misses: u32,
+    pub ping_interval: u64,
+    pub ping_count: u32,
+    pub replay_file: String,
+    pub replay_filter: Vec<ReplayFilter>,
+    pub replay_speed: f64,
+    pub replay_sink: Option<String>,
+    pub port_offset: usize,
+    pub impair_loss: f64,
+    pub impair_duplicate: f64,
+    pub impair_reorder: f64,
+    pub impair_delay: u64,
+    pub impair_jitter: u64,
     pub driverless: bool,
     pub mtu: usize,
     pub max_clients: usize,
+    pub max_clients_file: Option<String>,
+    pub slot_policy: SlotPolicy,
     pub dir_prefix: String,
 }
 
 pub fn run(mode: Mode, args: Arguments) {
     let running = RUNNING.clone();
-    if args.driverless {
+    if let Mode::Replay | Mode::Impair = mode {
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst)).unwrap();
+        start_instance(RUNNING.clone(), mode, &args);
+    } else if args.driverless {
         info!("Skipping driver launch...");
-        start_instance(running, mode, &args);
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst)).unwrap();
+        start_instance(RUNNING.clone(), mode, &args);
     } else {
         let driver_path = extract_driver();
 
@@ -80,9 +160,10 @@ pub fn run(mode: Mode, args: Arguments) {
                 .spawn()
                 .expect("Error spawning Aeron driver process")
         } else {
+            // The shell is replaced by the driver, which is then the child killed on exit
             Command::new("sh")
                 .arg("-c")
-                .arg(command.as_str())
+                .arg(format!("exec {}", command))
                 .spawn()
                 .expect("Error spawning Aeron driver process")
         };
@@ -90,13 +171,10 @@ pub fn run(mode: Mode, args: Arguments) {
         let transitory_duration = Duration::from_millis(1000);
         std::thread::sleep(transitory_duration);
 
+        // The driver outlives the instance, so that it can close its publications and subscriptions
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst)).unwrap();
         start_instance(RUNNING.clone(), mode, &args);
-
-        ctrlc::set_handler(move || {
-            running.store(false, Ordering::SeqCst);
-            child.kill().unwrap();
-        })
-        .unwrap();
+        child.kill().unwrap();
     }
 }
 
@@ -104,6 +182,12 @@ fn start_instance(running: Arc<AtomicBool>, mode: Mode, args: &Arguments) {
     match mode {
         Mode::Client => Client::instance(args).start(running),
         Mode::Server => Server::instance(args).start(running),
+        Mode::Replay => match Replay::instance(args) {
+            Ok(replay) => replay.start(running),
+            Err(e) => error!("{}", e),
+        },
+        Mode::Impair => Impair::instance(args).start(running),
+        Mode::Ping => Client::instance(args).ping(running),
     }
 }
 
@@ -118,14 +202,24 @@ fn extract_driver() -> String {
 
 fn attach_endpoint(args: &Arguments) -> UdpSocket {
     let endpoint = args.endpoint.to_owned();
+    let activated = systemd::activated_socket();
+    if let Some(address) = activated
+        .as_ref()
+        .and_then(|socket| socket.local_addr().ok())
+    {
+        info!(
+            "Using the endpoint socket {} passed by the service manager",
+            address
+        );
+    }
     let socket = if args.listen {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let socket = activated.unwrap_or_else(|| UdpSocket::bind("0.0.0.0:0").unwrap());
         socket
             .connect(endpoint.to_owned())
             .expect("Failed to connect to endpoint");
         socket
     } else {
-        UdpSocket::bind(endpoint).expect("Error binding socket input")
+        activated.unwrap_or_else(|| UdpSocket::bind(endpoint).expect("Error binding socket input"))
     };
 
     socket
@@ -133,3 +227,90 @@ fn attach_endpoint(args: &Arguments) -> UdpSocket {
         .expect("Failed to enter non-blocking mode");
     socket
 }
+
+/// Aeron IPC channel of one direction of the traffic between a client and a server on the same
+/// host, on the stream of the port standing for it. Terms are as long as the UDP ones, instead of
+/// the 64 MiB of IPC by default.
+fn ipc_channel(port: usize) -> String {
+    format!("aeron:ipc?term-length=16m|stream-id={}", port)
+}
+
+fn endpoint_packet(args: &Arguments, datagram: &Datagram, destination: SocketAddr) -> Vec<u8> {
+    if args.proxy_protocol {
+        let mut packet = proxy::header_v2(&datagram.metadata, destination);
+        packet.extend_from_slice(&datagram.payload);
+        packet
+    } else {
+        datagram.payload.clone()
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_arguments(endpoint: &str, listen: bool) -> Arguments {
+    Arguments {
+        port: 40123,
+        control: 32104,
+        single_port: false,
+        ipc: false,
+        port_range: 40124..=40133,
+        control_range: 32105..=32114,
+        server: String::from("127.0.0.1"),
+        servers: vec![String::from("127.0.0.1")],
+        server_selection: ServerSelection::Ordered,
+        max_redirects: 3,
+        redirect: vec![],
+        drain_file: None,
+        public: String::from("127.0.0.1"),
+        interface: String::new(),
+        interfaces: vec![],
+        multipath: MultipathMode::Redundant,
+        sforward: String::from("endpoint=0.0.0.0:40123"),
+        sbackward: String::from("control=127.0.0.1:32104|control-mode=dynamic"),
+        cforward: String::from("endpoint=127.0.0.1:40123"),
+        cbackward: String::from("endpoint=0.0.0.0:0|control=127.0.0.1:32104|control-mode=dynamic"),
+        listen,
+        endpoint: endpoint.to_owned(),
+        accept: vec![],
+        allow: None,
+        deny: None,
+        proxy_protocol: false,
+        compression: vec![Compression::None],
+        compression_threshold: 64,
+        reliability: Reliability::Reliable,
+        max_age: 0,
+        fec: Redundancy::default(),
+        capture: None,
+        limit_up: RateLimit::default(),
+        limit_down: RateLimit::default(),
+        global_limit_up: RateLimit::default(),
+        global_limit_down: RateLimit::default(),
+        limit_action: LimitAction::Drop,
+        identity: String::from("test"),
+        identities: None,
+        accounting: None,
+        daily_quota: Quota::default(),
+        monthly_quota: Quota::default(),
+        quota_action: QuotaAction::Reject,
+        quota_throttle: RateLimit::default(),
+        heartbeat_interval: 50,
+        heartbeat_misses: 3,
+        ping_interval: 0,
+        ping_count: 0,
+        replay_file: String::new(),
+        replay_filter: vec![],
+        replay_speed: 1.0,
+        replay_sink: None,
+        port_offset: 0,
+        impair_loss: 0.0,
+        impair_duplicate: 0.0,
+        impair_reorder: 0.0,
+        impair_delay: 0,
+        impair_jitter: 0,
+        driverless: true,
+        mtu: 1500,
+        max_clients: 10,
+        max_clients_file: None,
+        slot_policy: SlotPolicy::FirstFree,
+        dir_prefix: String::new(),
+    }
+}
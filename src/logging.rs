@@ -0,0 +1,100 @@
+use std::cell::Cell;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use tracing_subscriber::EnvFilter;
+
+/// Interval during which the repetitions of a message are counted rather than logged
+pub const REPEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How log events are written to the standard error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, prefixed by the spans they occur in
+    Text,
+    /// One JSON object per line, the fields of the spans included
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}", s)),
+        }
+    }
+}
+
+/// Installs the subscriber of the log events, filtered by the `RUST_LOG` environment variable
+/// and at the info level by default. The records logged by the dependencies through `log` are
+/// turned into events too.
+pub fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+/// Keeps a message repeated at every packet, like a send error, from flooding the log: it is
+/// logged at most once per interval, along with the number of repetitions suppressed since.
+pub struct Throttle {
+    interval: Duration,
+    logged: Cell<Option<Instant>>,
+    suppressed: Cell<u64>,
+}
+
+impl Throttle {
+    pub fn new(interval: Duration) -> Self {
+        Throttle {
+            interval,
+            logged: Cell::new(None),
+            suppressed: Cell::new(0),
+        }
+    }
+
+    /// Number of repetitions suppressed since the message was last logged, when it is to be
+    /// logged this time
+    pub fn admit(&self) -> Option<u64> {
+        match self.logged.get() {
+            Some(logged) if logged.elapsed() < self.interval => {
+                self.suppressed.set(self.suppressed.get() + 1);
+                None
+            }
+            _ => {
+                self.logged.set(Some(Instant::now()));
+                Some(self.suppressed.replace(0))
+            }
+        }
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(REPEAT_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Duration::from_millis(50));
+        assert_eq!(throttle.admit(), Some(0));
+        assert_eq!(throttle.admit(), None);
+        assert_eq!(throttle.admit(), None);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(throttle.admit(), Some(2));
+        assert_eq!(throttle.admit(), None);
+    }
+}
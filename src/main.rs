@@ -1,14 +1,12 @@
 use std::env;
 
 use getopts::Options;
-use log::info;
+use tracing::{error, info};
 
 use platform_dirs::AppDirs;
-use rudp_tunnel::{run, Arguments, Mode};
+use rudp_tunnel::{init_logging, run, Arguments, LogFormat, Mode};
 
 fn main() -> std::io::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-
     if let Some((mode, args)) = parse_args() {
         run(mode, args)
     }
@@ -16,7 +14,13 @@ fn main() -> std::io::Result<()> {
 }
 
 fn print_usage(program: &str, opts: Options) {
-    info!("{}", opts.usage(&format!("Usage: {} [options]", program)));
+    info!(
+        "{}",
+        opts.usage(&format!(
+            "Usage: {} [options]\n       {} replay FILE -e ENDPOINT [options]\n       {} impair -s SERVER --port-offset OFFSET [options]\n       {} ping -s SERVER [options]",
+            program, program, program, program
+        ))
+    );
 }
 
 fn parse_args() -> Option<(Mode, Arguments)> {
@@ -37,15 +41,43 @@ fn parse_args() -> Option<(Mode, Arguments)> {
         "The control port used for client NAT traversal. Defaults to 32104",
         "CONTROL",
     );
+    opts.optflag(
+        "",
+        "single-port",
+        "Serves every client through the port and the control port, with Aeron response channels, instead of a port pair per slot. Client and server must agree on it",
+    );
+    opts.optflag(
+        "",
+        "ipc",
+        "Connects a client and a server on the same host through Aeron IPC channels over shared memory instead of UDP, the ports standing for the streams of the channels. Client and server must agree on it and share the Aeron driver, the client joining the one of the server with --driverless",
+    );
     opts.optopt(
         "e",
         "endpoint",
         "Socket address where packets are sent/received, endpoint of the tunnel.",
         "ENDPOINT",
     );
-    opts.optopt("s", "server", "Public ip address of the server, implicitly defining this node as a client. Defaults to 0.0.0.0", "SERVER");
+    opts.optopt("s", "server", "Public ip address of the server, implicitly defining this node as a client. The client accepts a comma separated list of servers to fail over to when a handshake times out or is refused, or when the session is lost. Defaults to 0.0.0.0", "SERVER");
+    opts.optopt(
+        "",
+        "server-selection",
+        "How the client picks the server to fail over to, among ordered, the next in the list, and latency, the one with the lowest handshake latency measured so far. Defaults to ordered",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "max-redirects",
+        "Redirects to other servers the client follows in a row before failing over. 0 disables them. Defaults to 3",
+        "COUNT",
+    );
     opts.optopt("u", "public", "Public ip address of this node, starting as server. Ignored if SERVER is specified. Defaults to 0.0.0.0", "PUBLIC");
-    opts.optopt("i", "interface", "Routing interface.", "INTERFACE");
+    opts.optopt("i", "interface", "Routing interface. The client accepts a comma separated list of interfaces to send through in parallel, the first being the primary one", "INTERFACE");
+    opts.optopt(
+        "",
+        "multipath",
+        "How the client spreads datagrams over several interfaces, among redundant, round-robin and primary-backup. Defaults to redundant",
+        "MODE",
+    );
     opts.optopt(
         "m",
         "mtu",
@@ -58,11 +90,256 @@ fn parse_args() -> Option<(Mode, Arguments)> {
         "Maximum number of simultaneously connected clients. Defaults to 10",
         "MAXCLIENTS",
     );
+    opts.optopt(
+        "",
+        "max-clients-file",
+        "File holding the maximum number of simultaneously connected clients, reloaded when modified to change it at runtime. Defaults to none",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "port-range",
+        "Ports the client slots are allocated from. Defaults to the MAXCLIENTS ports following the port",
+        "FIRST-LAST",
+    );
+    opts.optopt(
+        "",
+        "control-range",
+        "Control ports the client slots are allocated from. Defaults to as many ports as the port range following the control port",
+        "FIRST-LAST",
+    );
+    opts.optopt(
+        "",
+        "slot-policy",
+        "How the slot of a new client is picked among the free ones, among first-free, round-robin, least-recently-used, the one released the longest ago, and reserved, the one the client was first given, kept for it alone. Defaults to first-free",
+        "POLICY",
+    );
     opts.optflag(
         "l",
         "listen",
         "Defines whether to listen on the endpoint socket address instead of connecting",
     );
+    opts.optopt(
+        "a",
+        "accept",
+        "Comma separated list of ip addresses accepted as sources on the endpoint socket address. Defaults to any",
+        "ACCEPT",
+    );
+    opts.optopt(
+        "",
+        "allow",
+        "File listing the networks clients are allowed to connect from, one per line in CIDR notation. Reloaded when modified. Defaults to any",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "deny",
+        "File listing the networks clients are denied to connect from, one per line in CIDR notation. Reloaded when modified",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "redirect",
+        "Comma separated list of the public ip addresses of the other servers of the pool, which clients are redirected to in turn when this server is full or draining. Defaults to none",
+        "SERVERS",
+    );
+    opts.optopt(
+        "",
+        "drain-file",
+        "The server drains while this file exists, keeping its clients but turning new ones away",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "proxy-protocol",
+        "Prepend a PROXY protocol v2 header carrying the original source address to the packets sent to the endpoint",
+    );
+    opts.optopt(
+        "z",
+        "compression",
+        "Comma separated list of payload compression algorithms among lz4, zstd and none, in order of preference. The client requests them during the handshake, the server accepts them. Defaults to none for the client, lz4,zstd for the server",
+        "COMPRESSION",
+    );
+    opts.optopt(
+        "",
+        "compression-threshold",
+        "Payloads shorter than this are sent uncompressed. Defaults to 64 (bytes)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "reliability",
+        "Delivery of the datagrams through the tunnel, reliable or best-effort, the latter not holding datagrams back while lost ones are retransmitted. The client requests it during the handshake, the server accepts best-effort unless set to reliable. Defaults to reliable for the client, best-effort for the server",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "max-age",
+        "Datagrams sent are dropped by the other side rather than delivered later than this after being sent, which requires synchronized clocks. 0 disables it. Defaults to 0 (ms)",
+        "MILLIS",
+    );
+    opts.optopt(
+        "",
+        "fec",
+        "Forward error correction of the datagrams sent, as DATA:PARITY shards per group, lost datagrams being recovered by the other side from the parity shards of their group. Meant for best-effort delivery. Defaults to none",
+        "RATIO",
+    );
+    opts.optopt(
+        "w",
+        "capture",
+        "Write the datagrams flowing through the tunnel to the given pcapng file.",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "limit-up",
+        "Rate limit of the traffic received by the server from each client, as PACKETS:BYTES per second. Zero or omitted values are unlimited",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "limit-down",
+        "Rate limit of the traffic sent by the server to each client, as PACKETS:BYTES per second",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "global-limit-up",
+        "Rate limit of the traffic received by the server from all the clients, as PACKETS:BYTES per second",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "global-limit-down",
+        "Rate limit of the traffic sent by the server to all the clients, as PACKETS:BYTES per second",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "limit-action",
+        "What to do with the traffic exceeding the rate limits, either drop or delay. Defaults to drop",
+        "ACTION",
+    );
+    opts.optopt(
+        "",
+        "identity",
+        "Name of this client, which the server accounts the traffic to. Defaults to the host name",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "identities",
+        "File mapping the networks clients connect from to the identities their traffic is accounted to, one NETWORK IDENTITY pair per line. Other clients are accounted to their name at their address",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "accounting",
+        "Keep the traffic of each client by day and month in the given file, reloaded on restart.",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "daily-quota",
+        "Traffic each client can exchange with the server in a day, as PACKETS:BYTES. Zero or omitted values are unlimited",
+        "QUOTA",
+    );
+    opts.optopt(
+        "",
+        "monthly-quota",
+        "Traffic each client can exchange with the server in a month, as PACKETS:BYTES",
+        "QUOTA",
+    );
+    opts.optopt(
+        "",
+        "quota-action",
+        "What to do with the clients exceeding their quotas, either reject or throttle. Defaults to reject",
+        "ACTION",
+    );
+    opts.optopt(
+        "",
+        "quota-throttle",
+        "Rate limit of the clients exceeding their quotas when throttled, as PACKETS:BYTES per second. Defaults to 10:16384",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "heartbeat-interval",
+        "Heartbeats are exchanged when idle for this long, to keep NAT mappings open and detect dead peers. The server one applies to both sides, 0 disables them. Defaults to 1000 (ms)",
+        "MILLIS",
+    );
+    opts.optopt(
+        "",
+        "heartbeat-misses",
+        "Number of heartbeat intervals without receiving anything after which the other side is considered dead. Defaults to 5",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "ping-interval",
+        "Interval between the pings measuring the round trip time through the tunnel, 0 to measure none. Defaults to 1000 (ms)",
+        "MILLIS",
+    );
+    opts.optopt(
+        "",
+        "count",
+        "Number of pings sent by the ping command before stopping. Defaults to unlimited",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "filter",
+        "Replay only the UDP flows whose source or destination matches one of these comma separated ports, ip addresses or socket addresses.",
+        "FLOWS",
+    );
+    opts.optopt(
+        "",
+        "speed",
+        "Replay speed relative to the original timing, 0 to replay as fast as possible. Defaults to 1",
+        "SPEED",
+    );
+    opts.optopt(
+        "",
+        "sink",
+        "Socket address where the replayed packets are received from the far side of the tunnel, to report on delivery and latency.",
+        "SINK",
+    );
+    opts.optopt(
+        "",
+        "port-offset",
+        "Offset between the ports of the server and the ones relaying them. The server advertises its slot ports shifted by this offset in handshake responses, the impair command listens on them. Defaults to 0",
+        "OFFSET",
+    );
+    opts.optopt(
+        "",
+        "loss",
+        "Percentage of the relayed datagrams dropped by the impair command. Defaults to 0",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "duplicate",
+        "Percentage of the relayed datagrams duplicated by the impair command. Defaults to 0",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "reorder",
+        "Percentage of the relayed datagrams held back by the impair command so that the following ones overtake them. Defaults to 0",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "delay",
+        "Delay added by the impair command to each relayed datagram. Defaults to 0 (ms)",
+        "MILLIS",
+    );
+    opts.optopt(
+        "",
+        "jitter",
+        "Random variation of the delay added by the impair command, in both directions. Defaults to 0 (ms)",
+        "MILLIS",
+    );
     opts.optflag(
         "d",
         "driverless",
@@ -73,10 +350,63 @@ fn parse_args() -> Option<(Mode, Arguments)> {
         "nosharedmem",
         "Avoid using shared memory (/dev/shm) under Linux. Has no effect on other platforms.",
     );
+    opts.optopt(
+        "",
+        "log-format",
+        "Format of the log written to the standard error: text, or json for one object per line carrying the fields of the slot or session the event occurs in. The level is set by the RUST_LOG environment variable. Defaults to text",
+        "FORMAT",
+    );
 
     match opts.parse(&args[1..]) {
         Ok(matches) => {
+            init_logging(
+                matches
+                    .opt_str("log-format")
+                    .unwrap_or(String::from("text"))
+                    .parse()
+                    .expect("Cannot parse log format"),
+            );
             let is_server = !matches.opt_present("server");
+            let command = matches.free.first().map(|command| command.as_str());
+            let is_replay = command == Some("replay");
+            let is_impair = command == Some("impair");
+            let is_ping = command == Some("ping");
+            let replay_filter = match matches
+                .opt_str("filter")
+                .map(|f| f.split(',').map(|flow| flow.trim().parse()).collect())
+                .transpose()
+            {
+                Ok(filter) => filter.unwrap_or_default(),
+                Err(e) => {
+                    error!("{}", e);
+                    print_usage(program, opts);
+                    return None;
+                }
+            };
+            let percentage = |name: &str| {
+                matches
+                    .opt_str(name)
+                    .map(|p| p.parse::<f64>().expect("Cannot parse percentage") / 100.0)
+                    .unwrap_or(0.0)
+            };
+            let rate_limit = |name: &str| {
+                matches
+                    .opt_str(name)
+                    .map(|r| r.parse().expect("Cannot parse rate limit"))
+                    .unwrap_or_default()
+            };
+            let quota = |name: &str| {
+                matches
+                    .opt_str(name)
+                    .map(|q| q.parse().expect("Cannot parse quota"))
+                    .unwrap_or_default()
+            };
+            let millis = |name: &str| {
+                matches
+                    .opt_str(name)
+                    .map(|m| m.parse().expect("Cannot parse milliseconds"))
+                    .unwrap_or(0)
+            };
             let port = matches
                 .opt_str("port")
                 .map(|b| b.parse::<usize>().unwrap())
@@ -85,12 +415,23 @@ fn parse_args() -> Option<(Mode, Arguments)> {
                 .opt_str("control")
                 .map(|b| b.parse::<usize>().unwrap())
                 .unwrap_or(32104);
-            let server = matches.opt_str("server").unwrap_or(String::from("0.0.0.0"));
+            let servers: Vec<String> = matches
+                .opt_str("server")
+                .unwrap_or(String::from("0.0.0.0"))
+                .split(',')
+                .map(|server| server.trim().to_owned())
+                .collect();
+            let server = servers[0].clone();
             let public = matches.opt_str("public").unwrap_or(String::from("0.0.0.0"));
-            let interface = matches.opt_str("interface");
-            let interface = interface
-                .map(|i| format!("|interface={}", i))
-                .unwrap_or(String::new());
+            let interfaces: Vec<String> = matches
+                .opt_str("interface")
+                .map(|i| {
+                    i.split(',')
+                        .map(|i| format!("|interface={}", i.trim()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let interface = interfaces.first().cloned().unwrap_or_default();
             let mtu = matches
                 .opt_str("mtu")
                 .unwrap_or(String::from("1500"))
@@ -101,12 +442,52 @@ fn parse_args() -> Option<(Mode, Arguments)> {
                 .unwrap_or(String::from("10"))
                 .parse()
                 .expect("Cannot parse max clients");
+            let range = |name: &str, above: usize| {
+                matches.opt_str(name).map(|range| {
+                    let (first, last) = range
+                        .split_once('-')
+                        .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)))
+                        .unwrap_or_else(|| panic!("Cannot parse {}", name));
+                    if first <= above {
+                        panic!("The {} must be above its port", name);
+                    }
+                    first..=last
+                })
+            };
+            let port_range = range("port-range", port).unwrap_or(port + 1..=port + max_clients);
+            let control_range = range("control-range", control)
+                .unwrap_or(control + 1..=control + port_range.clone().count());
+            let ipc = matches.opt_present("ipc");
+            if ipc && matches.opt_present("single-port") {
+                panic!("The single port and IPC modes are exclusive");
+            }
             let arguments = Arguments {
                 port: port.to_owned(),
                 control: control.to_owned(),
+                single_port: matches.opt_present("single-port"),
+                ipc,
+                port_range,
+                control_range,
                 server: server.to_owned(),
+                servers,
+                server_selection: matches
+                    .opt_str("server-selection")
+                    .unwrap_or(String::from("ordered"))
+                    .parse()
+                    .expect("Cannot parse server selection"),
+                max_redirects: matches
+                    .opt_str("max-redirects")
+                    .unwrap_or(String::from("3"))
+                    .parse()
+                    .expect("Cannot parse max redirects"),
                 public: public.to_owned(),
                 interface: interface.to_owned(),
+                interfaces,
+                multipath: matches
+                    .opt_str("multipath")
+                    .unwrap_or(String::from("redundant"))
+                    .parse()
+                    .expect("Cannot parse multipath mode"),
                 sforward: format!("endpoint=0.0.0.0:{}{}", port, interface),
                 sbackward: format!(
                     "control={}:{}{}|control-mode=dynamic",
@@ -121,15 +502,145 @@ fn parse_args() -> Option<(Mode, Arguments)> {
                 endpoint: matches
                     .opt_str("endpoint")
                     .unwrap_or("0.0.0.0:0".to_string()),
+                accept: matches
+                    .opt_str("accept")
+                    .map(|a| {
+                        a.split(',')
+                            .map(|ip| ip.trim().parse().expect("Cannot parse accepted address"))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                allow: matches.opt_str("allow"),
+                deny: matches.opt_str("deny"),
+                redirect: matches
+                    .opt_str("redirect")
+                    .map(|r| {
+                        r.split(',')
+                            .map(|ip| ip.trim().parse().expect("Cannot parse redirect address"))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                drain_file: matches.opt_str("drain-file"),
+                proxy_protocol: matches.opt_present("proxy-protocol"),
+                compression: matches
+                    .opt_str("compression")
+                    .unwrap_or(String::from(if is_server { "lz4,zstd" } else { "none" }))
+                    .split(',')
+                    .map(|c| c.trim().parse().expect("Cannot parse compression"))
+                    .collect(),
+                compression_threshold: matches
+                    .opt_str("compression-threshold")
+                    .unwrap_or(String::from("64"))
+                    .parse()
+                    .expect("Cannot parse compression threshold"),
+                reliability: matches
+                    .opt_str("reliability")
+                    .unwrap_or(String::from(if is_server {
+                        "best-effort"
+                    } else {
+                        "reliable"
+                    }))
+                    .parse()
+                    .expect("Cannot parse reliability"),
+                max_age: matches
+                    .opt_str("max-age")
+                    .unwrap_or(String::from("0"))
+                    .parse()
+                    .expect("Cannot parse max age"),
+                fec: matches
+                    .opt_str("fec")
+                    .unwrap_or(String::from("0"))
+                    .parse()
+                    .expect("Cannot parse forward error correction ratio"),
+                capture: matches.opt_str("capture"),
+                limit_up: rate_limit("limit-up"),
+                limit_down: rate_limit("limit-down"),
+                global_limit_up: rate_limit("global-limit-up"),
+                global_limit_down: rate_limit("global-limit-down"),
+                limit_action: matches
+                    .opt_str("limit-action")
+                    .unwrap_or(String::from("drop"))
+                    .parse()
+                    .expect("Cannot parse limit action"),
+                identity: matches
+                    .opt_str("identity")
+                    .or_else(|| whoami::fallible::hostname().ok())
+                    .unwrap_or_default(),
+                identities: matches.opt_str("identities"),
+                accounting: matches.opt_str("accounting"),
+                daily_quota: quota("daily-quota"),
+                monthly_quota: quota("monthly-quota"),
+                quota_action: matches
+                    .opt_str("quota-action")
+                    .unwrap_or(String::from("reject"))
+                    .parse()
+                    .expect("Cannot parse quota action"),
+                quota_throttle: matches
+                    .opt_str("quota-throttle")
+                    .unwrap_or(String::from("10:16384"))
+                    .parse()
+                    .expect("Cannot parse quota throttle"),
+                heartbeat_interval: matches
+                    .opt_str("heartbeat-interval")
+                    .unwrap_or(String::from("1000"))
+                    .parse()
+                    .expect("Cannot parse heartbeat interval"),
+                heartbeat_misses: matches
+                    .opt_str("heartbeat-misses")
+                    .unwrap_or(String::from("5"))
+                    .parse()
+                    .expect("Cannot parse heartbeat misses"),
+                ping_interval: matches
+                    .opt_str("ping-interval")
+                    .unwrap_or(String::from("1000"))
+                    .parse()
+                    .expect("Cannot parse ping interval"),
+                ping_count: matches
+                    .opt_str("count")
+                    .map(|c| c.parse().expect("Cannot parse ping count"))
+                    .unwrap_or(0),
+                replay_file: matches.free.get(1).cloned().unwrap_or_default(),
+                replay_filter,
+                replay_speed: matches
+                    .opt_str("speed")
+                    .unwrap_or(String::from("1"))
+                    .parse()
+                    .expect("Cannot parse replay speed"),
+                replay_sink: matches.opt_str("sink"),
+                port_offset: matches
+                    .opt_str("port-offset")
+                    .map(|o| o.parse().expect("Cannot parse port offset"))
+                    .unwrap_or(0),
+                impair_loss: percentage("loss"),
+                impair_duplicate: percentage("duplicate"),
+                impair_reorder: percentage("reorder"),
+                impair_delay: millis("delay"),
+                impair_jitter: millis("jitter"),
                 driverless: matches.opt_present("driverless"),
                 mtu,
                 max_clients,
+                max_clients_file: matches.opt_str("max-clients-file"),
+                slot_policy: matches
+                    .opt_str("slot-policy")
+                    .unwrap_or(String::from("first-free"))
+                    .parse()
+                    .expect("Cannot parse slot policy"),
                 dir_prefix: get_dir_prefix(matches.opt_present("nosharedmem")),
             };
             info!("{:?}", arguments);
-            if matches.opt_present("help") {
+            if matches.opt_present("help")
+                || (is_replay
+                    && (arguments.replay_file.is_empty() || !matches.opt_present("endpoint")))
+                || ((is_impair || is_ping) && !matches.opt_present("server"))
+            {
                 print_usage(program, opts);
                 None
+            } else if is_replay {
+                Some((Mode::Replay, arguments))
+            } else if is_impair {
+                Some((Mode::Impair, arguments))
+            } else if is_ping {
+                Some((Mode::Ping, arguments))
             } else if is_server {
                 Some((Mode::Server, arguments))
             } else {
@@ -137,6 +648,7 @@ fn parse_args() -> Option<(Mode, Arguments)> {
             }
         }
         Err(_) => {
+            init_logging(LogFormat::Text);
             print_usage(program, opts);
             None
         }
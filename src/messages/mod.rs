@@ -1,6 +1,58 @@
 use serde::{Deserialize, Serialize};
 
 use rand::Rng;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+pub mod proxy;
+
+/// Leads all the handshake messages, telling them apart from the ones of unversioned releases
+pub const MAGIC: u32 = 0x5255_4450;
+
+/// Version of the handshake messages layout, to be increased on any incompatible change
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Oldest version of the clients this server can handshake with
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// Optional features, enabled when both the client and the server support them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const COMPRESSION: Capabilities = Capabilities(1);
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 1);
+    pub const PING: Capabilities = Capabilities(1 << 2);
+    pub const BEST_EFFORT: Capabilities = Capabilities(1 << 3);
+    pub const DEADLINE: Capabilities = Capabilities(1 << 4);
+    pub const MULTIPATH: Capabilities = Capabilities(1 << 5);
+    pub const FEC: Capabilities = Capabilities(1 << 6);
+    pub const REDIRECT: Capabilities = Capabilities(1 << 7);
+
+    /// Capabilities this release supports
+    pub const SUPPORTED: Capabilities = Capabilities(
+        Capabilities::COMPRESSION.0
+            | Capabilities::HEARTBEAT.0
+            | Capabilities::PING.0
+            | Capabilities::BEST_EFFORT.0
+            | Capabilities::DEADLINE.0
+            | Capabilities::MULTIPATH.0
+            | Capabilities::FEC.0
+            | Capabilities::REDIRECT.0,
+    );
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn without(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct FailureDetails {
@@ -8,20 +60,61 @@ pub struct FailureDetails {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
 pub enum Failure {
     HandshakeFailedServerFull(FailureDetails),
     HandshakeFailedTooManyConnections(FailureDetails),
+    HandshakeFailedQuotaExceeded(FailureDetails),
+    HandshakeFailedNotAllowed(FailureDetails),
+    /// The client protocol version is not supported, the server one is attached
+    HandshakeFailedIncompatibleVersion(FailureDetails, u16),
+    /// The server is full or draining, and points the client at another server of its pool
+    HandshakeRedirect(FailureDetails, IpAddr),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+/// Leading fields of the handshake requests, laid out the same way by every protocol version
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RequestPreamble {
+    pub magic: u32,
+    pub version: u16,
+    pub key: i32,
+}
+
+impl RequestPreamble {
+    pub fn decode(buffer: &[u8]) -> Option<RequestPreamble> {
+        bincode::deserialize::<RequestPreamble>(buffer)
+            .ok()
+            .filter(|preamble| preamble.magic == MAGIC)
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version)
+    }
+}
+
+/// The fields up to the key form the `RequestPreamble`, never to be changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HandshakeRequest {
+    pub magic: u32,
+    pub version: u16,
     pub key: i32,
+    pub capabilities: Capabilities,
+    pub compression: Vec<Compression>,
+    /// Name of the client the traffic is accounted to
+    pub identity: String,
 }
 
 impl HandshakeRequest {
     pub fn new() -> HandshakeRequest {
         let mut rng = rand::thread_rng();
-        HandshakeRequest { key: rng.gen() }
+        HandshakeRequest {
+            magic: MAGIC,
+            version: PROTOCOL_VERSION,
+            key: rng.gen(),
+            capabilities: Capabilities::SUPPORTED,
+            compression: vec![],
+            identity: String::new(),
+        }
     }
 }
 
@@ -36,6 +129,181 @@ pub struct HandshakeResponse {
     pub port: usize,
     pub control: usize,
     pub verification: i32,
+    pub capabilities: Capabilities,
+    pub compression: Compression,
+    /// Milliseconds between the heartbeats sent by both sides when idle, if negotiated
+    pub heartbeat_interval: u64,
+}
+
+/// Envelope of the handshake responses and failures, the latter being decodable whatever the
+/// protocol version of the server, since the `Failure` variants are only ever appended
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct HandshakeReply {
+    pub magic: u32,
+    pub version: u16,
+    pub result: Result<HandshakeResponse, Failure>,
+}
+
+impl HandshakeReply {
+    pub fn new(result: Result<HandshakeResponse, Failure>) -> Self {
+        HandshakeReply {
+            magic: MAGIC,
+            version: PROTOCOL_VERSION,
+            result,
+        }
+    }
+
+    pub fn decode(buffer: &[u8]) -> Option<Result<HandshakeResponse, Failure>> {
+        let (magic, version) = bincode::deserialize::<(u32, u16)>(buffer).ok()?;
+        if magic != MAGIC {
+            None
+        } else if version == PROTOCOL_VERSION {
+            bincode::deserialize::<HandshakeReply>(buffer)
+                .ok()
+                .map(|reply| reply.result)
+        } else {
+            bincode::deserialize::<(u32, u16, u32, Failure)>(buffer)
+                .ok()
+                .filter(|(_, _, result, _)| *result == 1)
+                .map(|(_, _, _, failure)| Err(failure))
+        }
+    }
+}
+
+/// Side of the tunnel a datagram entered it from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The server endpoint
+    Server,
+    /// The endpoint of the client publishing on the given session
+    Client(i32),
+}
+
+/// Details about the original sender of a datagram, carried across the tunnel along with it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub origin: Origin,
+    pub source: SocketAddr,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression {}", s)),
+        }
+    }
+}
+
+/// Delivery of the datagrams on the client slot channels. Best-effort ones are not retransmitted
+/// when lost, so that the ones after them are not held back meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reliability {
+    Reliable,
+    BestEffort,
+}
+
+impl FromStr for Reliability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reliable" => Ok(Reliability::Reliable),
+            "best-effort" => Ok(Reliability::BestEffort),
+            _ => Err(format!("Unknown reliability {}", s)),
+        }
+    }
+}
+
+impl Reliability {
+    /// Reliability agreed during the handshake
+    pub fn negotiated(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::BEST_EFFORT) {
+            Reliability::BestEffort
+        } else {
+            Reliability::Reliable
+        }
+    }
+
+    /// Parameter appended to the subscription channel URIs, receivers being the ones asking for
+    /// the retransmission of lost datagrams
+    pub fn channel_parameter(&self) -> &'static str {
+        match self {
+            Reliability::Reliable => "",
+            Reliability::BestEffort => "|reliable=false",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub metadata: Metadata,
+    pub compression: Compression,
+    pub payload: Vec<u8>,
+}
+
+/// Messages exchanged on the client slot channels
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Datagram(Datagram),
+    /// Sent when nothing else was for an interval, to keep the NAT mappings open and to let
+    /// the other side know that this one is alive
+    Heartbeat,
+    /// Answered with a pong carrying the same sequence and timestamp, to measure round trips
+    Ping {
+        sequence: u32,
+        timestamp: u64,
+    },
+    Pong {
+        sequence: u32,
+        timestamp: u64,
+    },
+    /// Datagram to drop rather than deliver once past its deadline, in microseconds since the
+    /// Unix epoch
+    Expiring {
+        deadline: u64,
+        datagram: Datagram,
+    },
+    /// Datagram sent over several paths, delivered once whichever it arrives through first
+    Sequenced {
+        sequence: u64,
+        deadline: Option<u64>,
+        datagram: Datagram,
+    },
+    /// Data shard of a forward error correction group, carrying a serialized frame
+    FecData {
+        group: u64,
+        index: u8,
+        frame: Vec<u8>,
+    },
+    /// Parity shard of a group of `data` data shards, from which the missing ones are recovered
+    FecParity {
+        group: u64,
+        index: u8,
+        data: u8,
+        parity: Vec<u8>,
+    },
+}
+
+impl Datagram {
+    pub fn new(origin: Origin, source: SocketAddr, payload: &[u8]) -> Datagram {
+        Datagram {
+            metadata: Metadata { origin, source },
+            compression: Compression::None,
+            payload: payload.to_vec(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -57,6 +325,9 @@ mod tests {
             port: 40124,
             control: 32105,
             verification: 123456,
+            capabilities: Capabilities::COMPRESSION,
+            compression: Compression::Lz4,
+            heartbeat_interval: 1000,
         };
         let bytes = bincode::serialize(&resp).expect("Failed to serialize");
         let deserialized: HandshakeResponse =
@@ -74,4 +345,60 @@ mod tests {
             _ => panic!("Expected HandshakeFailedServerFull"),
         }
     }
+
+    #[test]
+    fn test_versioning() {
+        let request = bincode::serialize(&HandshakeRequest::new()).unwrap();
+        let preamble = RequestPreamble::decode(&request).unwrap();
+        assert!(preamble.is_compatible());
+
+        // A future client, whose request layout is unknown past the preamble
+        let future = bincode::serialize(&(MAGIC, PROTOCOL_VERSION + 1, 42, "anything")).unwrap();
+        let preamble = RequestPreamble::decode(&future).unwrap();
+        assert_eq!(preamble.key, 42);
+        assert!(!preamble.is_compatible());
+
+        // An unversioned request starts with the key
+        let unversioned = bincode::serialize(&(42, vec![Compression::Lz4])).unwrap();
+        assert!(RequestPreamble::decode(&unversioned).is_none());
+
+        // Failures of a future server are still understood
+        let failure = Failure::HandshakeFailedIncompatibleVersion(
+            FailureDetails { session_id: 42 },
+            PROTOCOL_VERSION + 1,
+        );
+        let reply = HandshakeReply {
+            version: PROTOCOL_VERSION + 1,
+            ..HandshakeReply::new(Err(failure))
+        };
+        match HandshakeReply::decode(&bincode::serialize(&reply).unwrap()) {
+            Some(Err(Failure::HandshakeFailedIncompatibleVersion(details, version))) => {
+                assert_eq!(details.session_id, 42);
+                assert_eq!(version, PROTOCOL_VERSION + 1);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        assert!(Capabilities::SUPPORTED.contains(Capabilities::COMPRESSION));
+        assert!(!Capabilities::SUPPORTED
+            .without(Capabilities::HEARTBEAT)
+            .contains(Capabilities::HEARTBEAT));
+        assert_eq!(
+            Capabilities::SUPPORTED.intersection(Capabilities::default()),
+            Capabilities::default()
+        );
+    }
+
+    #[test]
+    fn test_datagram_serialization() {
+        // Session id 0 is as valid as any other, and not mistaken for the server
+        for origin in [Origin::Server, Origin::Client(0), Origin::Client(7)] {
+            let datagram = Datagram::new(origin, "10.0.0.1:19900".parse().unwrap(), &[1, 2, 3]);
+            let bytes = bincode::serialize(&datagram).expect("Failed to serialize");
+            let deserialized: Datagram =
+                bincode::deserialize(&bytes).expect("Failed to deserialize");
+            assert_eq!(deserialized.metadata.origin, origin);
+            assert_eq!(datagram, deserialized);
+        }
+    }
 }
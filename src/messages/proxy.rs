@@ -0,0 +1,96 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::messages::{Metadata, Origin};
+
+const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+const VERSION_COMMAND_PROXY: u8 = 0x21;
+const FAMILY_UDP_OVER_IPV4: u8 = 0x12;
+const FAMILY_UDP_OVER_IPV6: u8 = 0x22;
+const TYPE_UNIQUE_ID: u8 = 0x05;
+
+/// Encodes a PROXY protocol v2 header describing the original source of a datagram.
+/// The session id of the client it comes from, if any, travels as a `PP2_TYPE_UNIQUE_ID` TLV.
+pub fn header_v2(metadata: &Metadata, destination: SocketAddr) -> Vec<u8> {
+    let mut addresses = vec![];
+    let family = match (metadata.source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            addresses.extend_from_slice(&source.octets());
+            addresses.extend_from_slice(&destination.octets());
+            FAMILY_UDP_OVER_IPV4
+        }
+        (source, destination) => {
+            addresses.extend_from_slice(&to_ipv6(source).octets());
+            addresses.extend_from_slice(&to_ipv6(destination).octets());
+            FAMILY_UDP_OVER_IPV6
+        }
+    };
+    addresses.extend_from_slice(&metadata.source.port().to_be_bytes());
+    addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+    if let Origin::Client(session_id) = metadata.origin {
+        let client_id = session_id.to_be_bytes();
+        addresses.push(TYPE_UNIQUE_ID);
+        addresses.extend_from_slice(&(client_id.len() as u16).to_be_bytes());
+        addresses.extend_from_slice(&client_id);
+    }
+
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION_COMMAND_PROXY);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_v2_ipv4() {
+        let metadata = Metadata {
+            origin: Origin::Client(1),
+            source: "10.0.0.1:1000".parse().unwrap(),
+        };
+        let header = header_v2(&metadata, "127.0.0.1:2000".parse().unwrap());
+        assert_eq!(&header[..12], &SIGNATURE);
+        assert_eq!(header[12], VERSION_COMMAND_PROXY);
+        assert_eq!(header[13], FAMILY_UDP_OVER_IPV4);
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]), 19);
+        assert_eq!(&header[16..20], &[10, 0, 0, 1]);
+        assert_eq!(&header[20..24], &[127, 0, 0, 1]);
+        assert_eq!(&header[24..28], &[0x03, 0xE8, 0x07, 0xD0]);
+        assert_eq!(&header[28..], &[TYPE_UNIQUE_ID, 0, 4, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_header_v2_server_origin() {
+        let metadata = Metadata {
+            origin: Origin::Server,
+            source: "10.0.0.1:1000".parse().unwrap(),
+        };
+        let header = header_v2(&metadata, "127.0.0.1:2000".parse().unwrap());
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]), 12);
+        assert_eq!(header.len(), 28);
+    }
+
+    #[test]
+    fn test_header_v2_mixed_families() {
+        let metadata = Metadata {
+            origin: Origin::Client(1),
+            source: "10.0.0.1:1000".parse().unwrap(),
+        };
+        let header = header_v2(&metadata, "[::1]:2000".parse().unwrap());
+        assert_eq!(header[13], FAMILY_UDP_OVER_IPV6);
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]), 43);
+    }
+}
@@ -0,0 +1,155 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::deadline::Deadline;
+use crate::messages::{Datagram, Frame};
+
+/// Number of sequences behind the highest one received that are remembered, older ones being
+/// taken as duplicates
+const WINDOW: u64 = 1024;
+
+/// How the client spreads the datagrams it sends over its paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultipathMode {
+    /// Every datagram on every path
+    Redundant,
+    /// Each datagram on the next path in turn
+    RoundRobin,
+    /// Every datagram on the first path still connected
+    PrimaryBackup,
+}
+
+impl FromStr for MultipathMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redundant" => Ok(MultipathMode::Redundant),
+            "round-robin" => Ok(MultipathMode::RoundRobin),
+            "primary-backup" => Ok(MultipathMode::PrimaryBackup),
+            _ => Err(format!("Unknown multipath mode {}", s)),
+        }
+    }
+}
+
+/// Sequences received within the window behind the highest one
+#[derive(Default)]
+struct Window {
+    highest: Option<u64>,
+    seen: [u64; (WINDOW / 64) as usize],
+}
+
+impl Window {
+    fn bit(sequence: u64) -> (usize, u64) {
+        (((sequence % WINDOW) / 64) as usize, 1 << (sequence % 64))
+    }
+
+    /// Records a sequence, returns whether it was not received before
+    fn insert(&mut self, sequence: u64) -> bool {
+        match self.highest {
+            Some(highest) if sequence + WINDOW <= highest => return false,
+            Some(highest) if sequence > highest => {
+                if sequence - highest >= WINDOW {
+                    self.seen = Default::default();
+                } else {
+                    (highest + 1..=sequence).for_each(|skipped| {
+                        let (word, bit) = Window::bit(skipped);
+                        self.seen[word] &= !bit;
+                    });
+                }
+                self.highest = Some(sequence);
+            }
+            Some(_) => {}
+            None => self.highest = Some(sequence),
+        }
+        let (word, bit) = Window::bit(sequence);
+        let new = self.seen[word] & bit == 0;
+        self.seen[word] |= bit;
+        new
+    }
+}
+
+/// Sequence numbers of the datagrams exchanged with a client sending over several paths, so that
+/// the ones arriving through more than one path are delivered once
+#[derive(Default)]
+pub struct Multipath {
+    next: AtomicU64,
+    received: Mutex<Window>,
+    duplicates: AtomicU64,
+}
+
+impl Multipath {
+    /// Whether a sequence was already received, counting it as a duplicate if so
+    pub fn is_duplicate(&self, sequence: u64) -> bool {
+        let duplicate = !self.received.lock().unwrap().insert(sequence);
+        if duplicate {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+        }
+        duplicate
+    }
+
+    /// Duplicates received so far
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+}
+
+/// Frame of a datagram sent now, sequenced when it may travel over several paths
+pub fn frame(datagram: Datagram, deadline: &Deadline, multipath: Option<&Multipath>) -> Frame {
+    match multipath {
+        Some(multipath) => Frame::Sequenced {
+            sequence: multipath.next.fetch_add(1, Ordering::Relaxed),
+            deadline: deadline.deadline(),
+            datagram,
+        },
+        None => deadline.frame(datagram),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::Origin;
+
+    #[test]
+    fn test_window() {
+        let multipath = Multipath::default();
+        assert!(!multipath.is_duplicate(0));
+        assert!(multipath.is_duplicate(0));
+        assert!(!multipath.is_duplicate(2));
+        assert!(!multipath.is_duplicate(1));
+        assert!(multipath.is_duplicate(2));
+        assert!(!multipath.is_duplicate(2 + WINDOW - 1));
+        assert!(!multipath.is_duplicate(WINDOW));
+        // Too old to be told apart from a duplicate
+        assert!(multipath.is_duplicate(1));
+        // Slots reused past the window are cleared
+        assert!(!multipath.is_duplicate(10 * WINDOW + 1));
+        assert!(!multipath.is_duplicate(10 * WINDOW));
+        assert_eq!(multipath.duplicates(), 3);
+    }
+
+    #[test]
+    fn test_frame() {
+        let multipath = Multipath::default();
+        let datagram = Datagram::new(
+            Origin::Client(1),
+            "127.0.0.1:19900".parse().unwrap(),
+            b"state",
+        );
+        let deadline = Deadline::default();
+        assert!(matches!(
+            frame(datagram.clone(), &deadline, None),
+            Frame::Datagram(_)
+        ));
+        assert!(matches!(
+            frame(datagram.clone(), &deadline, Some(&multipath)),
+            Frame::Sequenced { sequence: 0, .. }
+        ));
+        assert!(matches!(
+            frame(datagram, &deadline, Some(&multipath)),
+            Frame::Sequenced { sequence: 1, .. }
+        ));
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::messages::Datagram;
+
+/// Datagrams waiting for tokens beyond this are dropped even when delaying
+const MAX_DEFERRED: usize = 1024;
+
+/// Packets and bytes per second allowed, zero meaning unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (packets, bytes) = parse_packets_bytes(s, "rate limit")?;
+        Ok(RateLimit { packets, bytes })
+    }
+}
+
+/// Parses `PACKETS:BYTES`, either of them can be omitted and is then zero
+pub fn parse_packets_bytes(s: &str, what: &str) -> Result<(u64, u64), String> {
+    let (packets, bytes) = s.split_once(':').unwrap_or((s, ""));
+    let parse = |value: &str| {
+        if value.is_empty() {
+            Ok(0)
+        } else {
+            value
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid {} {}: {}", what, s, e))
+        }
+    };
+    Ok((parse(packets)?, parse(bytes)?))
+}
+
+/// What happens to the traffic exceeding the rate limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitAction {
+    Drop,
+    Delay,
+}
+
+impl FromStr for LimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(LimitAction::Drop),
+            "delay" => Ok(LimitAction::Delay),
+            _ => Err(format!("Unknown limit action {}", s)),
+        }
+    }
+}
+
+/// Bucket holding up to one second worth of tokens
+#[derive(Clone, Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Option<Self> {
+        (rate > 0).then(|| TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+
+    /// Amounts larger than the bucket pass once it is full, not to be held back forever
+    fn allows(&self, amount: f64) -> bool {
+        self.tokens >= amount.min(self.rate)
+    }
+
+    fn consume(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    passed: usize,
+    delayed: usize,
+    dropped: usize,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        RateLimiter {
+            packets: TokenBucket::new(limit.packets),
+            bytes: TokenBucket::new(limit.bytes),
+            passed: 0,
+            delayed: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Changes the rates, keeping the counters and the tokens left up to the new rates
+    pub fn set_limit(&mut self, limit: &RateLimit) {
+        let tokens = |bucket: &Option<TokenBucket>| bucket.as_ref().map(|bucket| bucket.tokens);
+        let (packets, bytes) = (tokens(&self.packets), tokens(&self.bytes));
+        self.packets = TokenBucket::new(limit.packets);
+        self.bytes = TokenBucket::new(limit.bytes);
+        for (bucket, tokens) in [(&mut self.packets, packets), (&mut self.bytes, bytes)] {
+            if let (Some(bucket), Some(tokens)) = (bucket, tokens) {
+                bucket.tokens = tokens.min(bucket.rate);
+            }
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.packets.is_none() && self.bytes.is_none()
+    }
+
+    fn buckets(&mut self) -> impl Iterator<Item = (&mut TokenBucket, bool)> {
+        [(self.packets.as_mut(), true), (self.bytes.as_mut(), false)]
+            .into_iter()
+            .filter_map(|(bucket, packets)| bucket.map(|bucket| (bucket, packets)))
+    }
+
+    fn allows(&mut self, length: usize, now: Instant) -> bool {
+        self.buckets().all(|(bucket, packets)| {
+            bucket.refill(now);
+            bucket.allows(if packets { 1.0 } else { length as f64 })
+        })
+    }
+
+    fn consume(&mut self, length: usize) {
+        self.passed += 1;
+        self.buckets().for_each(|(bucket, packets)| {
+            bucket.consume(if packets { 1.0 } else { length as f64 })
+        });
+    }
+}
+
+impl fmt::Display for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} passed, {} delayed, {} dropped",
+            self.passed, self.delayed, self.dropped
+        )?;
+        if let Some(bucket) = &self.packets {
+            write!(f, ", {:.0}/{:.0} packet tokens", bucket.tokens, bucket.rate)?;
+        }
+        if let Some(bucket) = &self.bytes {
+            write!(f, ", {:.0}/{:.0} byte tokens", bucket.tokens, bucket.rate)?;
+        }
+        Ok(())
+    }
+}
+
+/// Admits a datagram of the given length when all the limiters have enough tokens,
+/// consuming them
+pub fn admit(limiters: &mut [&mut RateLimiter], length: usize) -> bool {
+    let now = Instant::now();
+    let allowed = limiters
+        .iter_mut()
+        .all(|limiter| limiter.allows(length, now));
+    if allowed {
+        limiters
+            .iter_mut()
+            .for_each(|limiter| limiter.consume(length));
+    }
+    allowed
+}
+
+/// Rate limiter of a traffic direction, keeping the datagrams delayed in arrival order
+#[derive(Clone, Debug)]
+pub struct Shaper {
+    pub limiter: RateLimiter,
+    action: LimitAction,
+    deferred: VecDeque<Datagram>,
+}
+
+impl Shaper {
+    pub fn new(limit: &RateLimit, action: LimitAction) -> Self {
+        Shaper {
+            limiter: RateLimiter::new(limit),
+            action,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    pub fn has_deferred(&self) -> bool {
+        !self.deferred.is_empty()
+    }
+
+    /// Returns the datagram when it can be sent right away along the global limiter,
+    /// otherwise it is deferred or dropped according to the limit action
+    pub fn shape(&mut self, global: &mut RateLimiter, datagram: Datagram) -> Option<Datagram> {
+        let length = datagram.payload.len();
+        if !self.has_deferred() && admit(&mut [&mut self.limiter, global], length) {
+            return Some(datagram);
+        }
+        if self.action == LimitAction::Delay && self.deferred.len() < MAX_DEFERRED {
+            self.limiter.delayed += 1;
+            global.delayed += 1;
+            self.deferred.push_back(datagram);
+        } else {
+            self.limiter.dropped += 1;
+            global.dropped += 1;
+        }
+        None
+    }
+
+    /// Takes the next deferred datagram, once the limiters have tokens for it
+    pub fn release(&mut self, global: &mut RateLimiter) -> Option<Datagram> {
+        let length = self.deferred.front()?.payload.len();
+        if admit(&mut [&mut self.limiter, global], length) {
+            self.deferred.pop_front()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::Origin;
+
+    fn datagram(length: usize) -> Datagram {
+        Datagram::new(
+            Origin::Client(1),
+            "127.0.0.1:1000".parse().unwrap(),
+            &vec![0; length],
+        )
+    }
+
+    #[test]
+    fn test_rate_limit_parsing() {
+        assert_eq!(
+            "100:65536".parse(),
+            Ok(RateLimit {
+                packets: 100,
+                bytes: 65536
+            })
+        );
+        assert_eq!(
+            ":1000".parse(),
+            Ok(RateLimit {
+                packets: 0,
+                bytes: 1000
+            })
+        );
+        assert!("many".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn test_shaping() {
+        let mut global = RateLimiter::new(&RateLimit::default());
+        let mut dropping = Shaper::new(&"2:".parse().unwrap(), LimitAction::Drop);
+        assert!(dropping.shape(&mut global, datagram(10)).is_some());
+        assert!(dropping.shape(&mut global, datagram(10)).is_some());
+        assert!(dropping.shape(&mut global, datagram(10)).is_none());
+        assert!(!dropping.has_deferred());
+        assert_eq!(dropping.limiter.dropped, 1);
+
+        let mut delaying = Shaper::new(&":100".parse().unwrap(), LimitAction::Delay);
+        assert!(delaying.shape(&mut global, datagram(80)).is_some());
+        assert!(delaying.shape(&mut global, datagram(80)).is_none());
+        assert!(delaying.has_deferred());
+        assert!(delaying.release(&mut global).is_none());
+        std::thread::sleep(std::time::Duration::from_millis(700));
+        assert_eq!(delaying.release(&mut global).unwrap().payload.len(), 80);
+        assert_eq!(delaying.limiter.passed, 2);
+        assert_eq!(global.delayed, 1);
+        assert_eq!(global.passed, 4);
+    }
+}
@@ -0,0 +1,337 @@
+mod pcap;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use tracing::{debug, error, info, warn};
+
+use crate::latency::LatencyStats;
+use crate::replay::pcap::{read_udp_packets, UdpPacket};
+use crate::Arguments;
+
+/// Replays the UDP flows of a capture file into a tunnel endpoint, preserving the original
+/// inter-packet timing scaled by the given speed. When a sink address is given, the datagrams
+/// delivered by the far side of the tunnel are received there and compared with the sent ones.
+pub struct Replay {
+    packets: Vec<UdpPacket>,
+    target: SocketAddr,
+    speed: f64,
+    sink: Option<UdpSocket>,
+    linger: Duration,
+}
+
+/// Flow of the capture to replay, matching the packets whose source or destination is on the
+/// port, the ip address or the socket address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayFilter {
+    Port(u16),
+    Address(SocketAddr),
+    Ip(IpAddr),
+}
+
+impl FromStr for ReplayFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(port) = s.parse() {
+            Ok(ReplayFilter::Port(port))
+        } else if let Ok(addr) = s.parse() {
+            Ok(ReplayFilter::Address(addr))
+        } else if let Ok(ip) = s.parse() {
+            Ok(ReplayFilter::Ip(ip))
+        } else {
+            Err(format!("Cannot parse replay filter {}", s))
+        }
+    }
+}
+
+/// Delivery of the replayed datagrams to the sink, which are told apart by their payload
+#[derive(Default)]
+struct Report {
+    in_flight: HashMap<Vec<u8>, VecDeque<(usize, Instant)>>,
+    last_received_index: Option<usize>,
+    sent: usize,
+    received: usize,
+    unexpected: usize,
+    reordered: usize,
+    latencies: LatencyStats,
+}
+
+impl Replay {
+    pub fn instance(args: &Arguments) -> Result<Self, String> {
+        let bytes = fs::read(&args.replay_file)
+            .map_err(|e| format!("Error reading capture file {}: {}", args.replay_file, e))?;
+        let mut packets: Vec<UdpPacket> = read_udp_packets(&bytes)
+            .map_err(|e| format!("Error parsing capture file {}: {}", args.replay_file, e))?
+            .into_iter()
+            .filter(|packet| {
+                args.replay_filter.is_empty()
+                    || args
+                        .replay_filter
+                        .iter()
+                        .any(|filter| filter.matches(packet))
+            })
+            .collect();
+        packets.sort_by_key(|packet| packet.timestamp);
+        info!(
+            "Loaded {} UDP datagrams from {}",
+            packets.len(),
+            args.replay_file
+        );
+
+        let sink = args
+            .replay_sink
+            .as_ref()
+            .map(|sink| {
+                UdpSocket::bind(sink)
+                    .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+                    .map_err(|e| format!("Error binding sink socket {}: {}", sink, e))
+            })
+            .transpose()?;
+
+        Ok(Replay {
+            packets,
+            target: args
+                .endpoint
+                .parse()
+                .map_err(|e| format!("Cannot parse endpoint socket address: {}", e))?,
+            speed: args.replay_speed,
+            sink,
+            linger: Duration::from_secs(2),
+        })
+    }
+
+    pub fn start(self, running: Arc<AtomicBool>) {
+        let mut sockets: HashMap<SocketAddr, UdpSocket> = HashMap::new();
+        let mut report = Report::default();
+
+        info!(
+            "Replaying to endpoint {} at {}x speed",
+            self.target, self.speed
+        );
+        let first_timestamp = self.packets.first().map(|packet| packet.timestamp);
+        let start = Instant::now();
+        for (index, packet) in self.packets.iter().enumerate() {
+            let due = due(
+                start,
+                packet.timestamp - first_timestamp.unwrap(),
+                self.speed,
+            );
+            while running.load(Ordering::SeqCst) && Instant::now() < due {
+                self.receive(&mut report);
+                std::thread::sleep(
+                    Duration::from_millis(1).min(due.saturating_duration_since(Instant::now())),
+                );
+            }
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let local = if self.target.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = match sockets.entry(packet.source) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match UdpSocket::bind(local) {
+                    Ok(socket) => entry.insert(socket),
+                    Err(e) => {
+                        error!("Can't bind replay socket of {}: {}", packet.source, e);
+                        continue;
+                    }
+                },
+            };
+            match socket.send_to(&packet.payload, self.target) {
+                Ok(_) => {
+                    debug!(
+                        "Replayed {} bytes of {} -> {}",
+                        packet.payload.len(),
+                        packet.source,
+                        packet.destination
+                    );
+                    report.sent(index, &packet.payload, Instant::now());
+                }
+                Err(e) => error!("Can't replay packet to endpoint: {}", e),
+            }
+        }
+
+        let elapsed = start.elapsed();
+        let linger = Instant::now() + self.linger;
+        while self.sink.is_some() && running.load(Ordering::SeqCst) && Instant::now() < linger {
+            self.receive(&mut report);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        info!(
+            "Replayed {} datagrams in {:.3}s",
+            report.sent,
+            elapsed.as_secs_f64()
+        );
+        if self.sink.is_some() {
+            report.log();
+        }
+    }
+
+    fn receive(&self, report: &mut Report) {
+        let sink = match &self.sink {
+            Some(sink) => sink,
+            None => return,
+        };
+        let mut recv_buff = vec![0; 65535];
+        loop {
+            match sink.recv_from(&mut recv_buff) {
+                Ok((n, _)) => report.received(&recv_buff[..n], Instant::now()),
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::WouldBlock {
+                        error!("Error receiving from sink {:?}", err)
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// When the packet at the given offset from the start of the capture is due to be replayed
+fn due(start: Instant, offset: Duration, speed: f64) -> Instant {
+    if speed > 0.0 {
+        start + offset.div_f64(speed)
+    } else {
+        start
+    }
+}
+
+impl Report {
+    fn sent(&mut self, index: usize, payload: &[u8], instant: Instant) {
+        self.sent += 1;
+        self.in_flight
+            .entry(payload.to_vec())
+            .or_default()
+            .push_back((index, instant));
+    }
+
+    fn received(&mut self, payload: &[u8], instant: Instant) {
+        let sent = self
+            .in_flight
+            .get_mut(payload)
+            .and_then(|instants| instants.pop_front());
+        match sent {
+            Some((index, sent)) => {
+                self.received += 1;
+                self.latencies
+                    .record(instant.saturating_duration_since(sent));
+                if self.last_received_index.is_some_and(|last| last > index) {
+                    self.reordered += 1;
+                }
+                self.last_received_index = Some(index);
+            }
+            None => self.unexpected += 1,
+        }
+    }
+
+    fn lost(&self) -> usize {
+        self.sent - self.received
+    }
+
+    fn log(&self) {
+        let lost = self.lost();
+        info!(
+            "Received {}/{} datagrams ({} lost, {} reordered, {} unexpected)",
+            self.received, self.sent, lost, self.reordered, self.unexpected
+        );
+        if lost > 0 {
+            warn!(
+                "{:.2}% of the replayed datagrams were not delivered",
+                lost as f64 * 100.0 / self.sent as f64
+            );
+        }
+        if !self.latencies.is_empty() {
+            info!("Latency {}", self.latencies);
+        }
+    }
+}
+
+impl ReplayFilter {
+    fn matches(&self, packet: &UdpPacket) -> bool {
+        let endpoints = [packet.source, packet.destination];
+        match self {
+            ReplayFilter::Port(port) => endpoints.iter().any(|endpoint| endpoint.port() == *port),
+            ReplayFilter::Address(addr) => endpoints.contains(addr),
+            ReplayFilter::Ip(ip) => endpoints.iter().any(|endpoint| endpoint.ip() == *ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(source: &str, destination: &str) -> UdpPacket {
+        UdpPacket {
+            timestamp: Duration::ZERO,
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            payload: vec![],
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let packet = packet("10.0.0.1:5000", "[::1]:53");
+        let matches = |filter: &str| filter.parse::<ReplayFilter>().unwrap().matches(&packet);
+        assert!(matches("5000"));
+        assert!(matches("53"));
+        assert!(!matches("5001"));
+        assert!(matches("10.0.0.1:5000"));
+        assert!(matches("[::1]:53"));
+        assert!(!matches("10.0.0.1:53"));
+        assert!(matches("10.0.0.1"));
+        assert!(matches("::1"));
+        assert!(!matches("10.0.0.2"));
+        assert_eq!(
+            "10.0.0.1:http".parse::<ReplayFilter>(),
+            Err(String::from("Cannot parse replay filter 10.0.0.1:http"))
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let start = Instant::now();
+        let mut report = Report::default();
+        for index in 0..5 {
+            report.sent(index, &[index as u8], start);
+        }
+        report.sent(5, &[0], start);
+        report.received(&[1], start + Duration::from_millis(10));
+        report.received(&[0], start + Duration::from_millis(20));
+        report.received(&[2], start + Duration::from_millis(30));
+        report.received(&[0], start + Duration::from_millis(40));
+        report.received(&[0], start + Duration::from_millis(50));
+        report.received(&[9], start + Duration::from_millis(60));
+        assert_eq!(report.received, 4);
+        assert_eq!(report.lost(), 2);
+        assert_eq!(report.reordered, 1);
+        assert_eq!(report.unexpected, 2);
+        assert!(report
+            .latencies
+            .to_string()
+            .starts_with("min/avg/max/p50/p90/p99 = 10.000/25.000/40.000/20.000/40.000/40.000 ms"));
+    }
+
+    #[test]
+    fn test_due() {
+        let start = Instant::now();
+        let offset = Duration::from_millis(100);
+        assert_eq!(due(start, offset, 1.0), start + offset);
+        assert_eq!(due(start, offset, 2.0), start + Duration::from_millis(50));
+        assert_eq!(due(start, offset, 0.5), start + Duration::from_millis(200));
+        assert_eq!(due(start, offset, 0.0), start);
+    }
+}
@@ -0,0 +1,333 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_OPTION_IF_TSRESOL: u16 = 9;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// A UDP datagram read from a capture file, timestamped since the epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpPacket {
+    pub timestamp: Duration,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+struct Interface {
+    link_type: u32,
+    nanos_per_unit: u64,
+}
+
+/// Reads the UDP datagrams out of a pcap or pcapng capture, skipping any other traffic
+pub fn read_udp_packets(bytes: &[u8]) -> io::Result<Vec<UdpPacket>> {
+    let magic = read_u32(bytes, 0, false)?;
+    match magic {
+        PCAPNG_SECTION_HEADER => read_pcapng(bytes),
+        _ => read_pcap(bytes),
+    }
+}
+
+fn read_pcap(bytes: &[u8]) -> io::Result<Vec<UdpPacket>> {
+    let (big_endian, nanos) = match (read_u32(bytes, 0, false)?, read_u32(bytes, 0, true)?) {
+        (PCAP_MAGIC_MICROS, _) => (false, false),
+        (PCAP_MAGIC_NANOS, _) => (false, true),
+        (_, PCAP_MAGIC_MICROS) => (true, false),
+        (_, PCAP_MAGIC_NANOS) => (true, true),
+        _ => return Err(invalid("Not a pcap or pcapng file")),
+    };
+    let link_type = read_u32(bytes, 20, big_endian)? & 0x0FFF_FFFF;
+
+    let mut packets = vec![];
+    let mut offset = 24;
+    while offset + 16 <= bytes.len() {
+        let seconds = read_u32(bytes, offset, big_endian)? as u64;
+        let fraction = read_u32(bytes, offset + 4, big_endian)? as u64;
+        let captured_length = read_u32(bytes, offset + 8, big_endian)? as usize;
+        let data = slice(bytes, offset + 16, captured_length)?;
+        let timestamp = Duration::from_secs(seconds)
+            + Duration::from_nanos(if nanos { fraction } else { fraction * 1000 });
+        if let Some(packet) = parse_link(link_type, data, timestamp) {
+            packets.push(packet);
+        }
+        offset += 16 + captured_length;
+    }
+    Ok(packets)
+}
+
+fn read_pcapng(bytes: &[u8]) -> io::Result<Vec<UdpPacket>> {
+    let mut packets = vec![];
+    let mut interfaces: Vec<Interface> = vec![];
+    let mut big_endian = false;
+    let mut offset = 0;
+    while offset + 12 <= bytes.len() {
+        let block_type = read_u32(bytes, offset, big_endian)?;
+        if block_type == PCAPNG_SECTION_HEADER {
+            big_endian = read_u32(bytes, offset + 8, false)? != PCAPNG_BYTE_ORDER_MAGIC;
+            interfaces.clear();
+        }
+        let block_length = read_u32(bytes, offset + 4, big_endian)? as usize;
+        if block_length < 12 {
+            return Err(invalid("Invalid pcapng block length"));
+        }
+        let body = slice(bytes, offset + 8, block_length - 12)?;
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = read_u16(body, 0, big_endian)? as u32;
+                let nanos_per_unit = read_options(body, 8, big_endian)
+                    .into_iter()
+                    .find(|(code, _)| *code == PCAPNG_OPTION_IF_TSRESOL)
+                    .and_then(|(_, value)| value.first().copied())
+                    .map(timestamp_resolution)
+                    .unwrap_or(1000);
+                interfaces.push(Interface {
+                    link_type,
+                    nanos_per_unit,
+                });
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = interfaces
+                    .get(read_u32(body, 0, big_endian)? as usize)
+                    .ok_or_else(|| invalid("Packet on undeclared interface"))?;
+                let timestamp = ((read_u32(body, 4, big_endian)? as u64) << 32)
+                    | read_u32(body, 8, big_endian)? as u64;
+                let captured_length = read_u32(body, 12, big_endian)? as usize;
+                let data = slice(body, 20, captured_length)?;
+                let timestamp = Duration::from_nanos(timestamp * interface.nanos_per_unit);
+                if let Some(packet) = parse_link(interface.link_type, data, timestamp) {
+                    packets.push(packet);
+                }
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let interface = interfaces
+                    .first()
+                    .ok_or_else(|| invalid("Packet on undeclared interface"))?;
+                let original_length = read_u32(body, 0, big_endian)? as usize;
+                let data = slice(body, 4, original_length.min(body.len() - 4))?;
+                if let Some(packet) = parse_link(interface.link_type, data, Duration::ZERO) {
+                    packets.push(packet);
+                }
+            }
+            _ => {}
+        }
+        offset += block_length;
+    }
+    Ok(packets)
+}
+
+fn read_options(body: &[u8], mut offset: usize, big_endian: bool) -> Vec<(u16, &[u8])> {
+    let mut options = vec![];
+    while let (Ok(code), Ok(length)) = (
+        read_u16(body, offset, big_endian),
+        read_u16(body, offset + 2, big_endian),
+    ) {
+        if code == 0 {
+            break;
+        }
+        match slice(body, offset + 4, length as usize) {
+            Ok(value) => options.push((code, value)),
+            Err(_) => break,
+        }
+        offset += 4 + (length as usize).div_ceil(4) * 4;
+    }
+    options
+}
+
+fn timestamp_resolution(resolution: u8) -> u64 {
+    let exponent = (resolution & 0x7F) as u32;
+    if resolution & 0x80 == 0 {
+        10u64
+            .checked_pow(9u32.saturating_sub(exponent))
+            .unwrap_or(1)
+    } else {
+        (1_000_000_000u64 >> exponent.min(63)).max(1)
+    }
+}
+
+fn parse_link(link_type: u32, data: &[u8], timestamp: Duration) -> Option<UdpPacket> {
+    let ip = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => data,
+        LINKTYPE_NULL | LINKTYPE_LOOP => data.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ether_type = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+            while ether_type == ETHERTYPE_VLAN {
+                offset += 4;
+                ether_type = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+            }
+            if ether_type != ETHERTYPE_IPV4 && ether_type != ETHERTYPE_IPV6 {
+                return None;
+            }
+            data.get(offset + 2..)?
+        }
+        LINKTYPE_LINUX_SLL => data.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => data.get(20..)?,
+        _ => return None,
+    };
+    parse_ip(ip, timestamp)
+}
+
+fn parse_ip(data: &[u8], timestamp: Duration) -> Option<UdpPacket> {
+    let (source, destination, udp) = match data.first()? >> 4 {
+        4 => {
+            let header_length = ((data[0] & 0x0F) as usize) * 4;
+            let fragment = u16::from_be_bytes([*data.get(6)?, *data.get(7)?]);
+            if *data.get(9)? != IP_PROTOCOL_UDP || fragment & 0x3FFF != 0 {
+                return None;
+            }
+            let source: [u8; 4] = data.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = data.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(source)),
+                IpAddr::V4(Ipv4Addr::from(destination)),
+                data.get(header_length..)?,
+            )
+        }
+        6 => {
+            if *data.get(6)? != IP_PROTOCOL_UDP {
+                return None;
+            }
+            let source: [u8; 16] = data.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = data.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(source)),
+                IpAddr::V6(Ipv6Addr::from(destination)),
+                data.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+    let source_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let destination_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let length = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+    let payload = udp.get(8..length.max(8).min(udp.len()))?;
+    Some(UdpPacket {
+        timestamp,
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        payload: payload.to_vec(),
+    })
+}
+
+fn slice(bytes: &[u8], offset: usize, length: usize) -> io::Result<&[u8]> {
+    bytes
+        .get(offset..offset + length)
+        .ok_or_else(|| invalid("Truncated capture"))
+}
+
+fn read_u16(bytes: &[u8], offset: usize, big_endian: bool) -> io::Result<u16> {
+    let value: [u8; 2] = slice(bytes, offset, 2)?.try_into().unwrap();
+    Ok(if big_endian {
+        u16::from_be_bytes(value)
+    } else {
+        u16::from_le_bytes(value)
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize, big_endian: bool) -> io::Result<u32> {
+    let value: [u8; 4] = slice(bytes, offset, 4)?.try_into().unwrap();
+    Ok(if big_endian {
+        u32::from_be_bytes(value)
+    } else {
+        u32::from_le_bytes(value)
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{Capture, Direction};
+    use std::env::temp_dir;
+
+    #[test]
+    fn test_read_pcap_ethernet() {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&PCAP_MAGIC_MICROS.to_le_bytes());
+        bytes.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0, 0]);
+        bytes.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0, 0, 33, 0, 0, 0, 0, 64, IP_PROTOCOL_UDP, 0, 0]);
+        frame.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend_from_slice(&[0x03, 0xE8, 0x07, 0xD0, 0, 13, 0, 0]);
+        frame.extend_from_slice(b"hello");
+
+        bytes.extend_from_slice(&10u32.to_le_bytes());
+        bytes.extend_from_slice(&500u32.to_le_bytes());
+        bytes.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        bytes.extend(frame);
+
+        let packets = read_udp_packets(&bytes).unwrap();
+        assert_eq!(
+            packets,
+            vec![UdpPacket {
+                timestamp: Duration::from_micros(10_000_500),
+                source: "10.0.0.1:1000".parse().unwrap(),
+                destination: "10.0.0.2:2000".parse().unwrap(),
+                payload: b"hello".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_captured_pcapng() {
+        let mut path = temp_dir();
+        path.push(format!("rudp-tunnel-replay-{}.pcapng", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+
+        let capture = Capture::create(&path);
+        capture.record(
+            Direction::Inbound,
+            None,
+            1,
+            "[::1]:1000".parse().unwrap(),
+            "[::1]:2000".parse().unwrap(),
+            b"first",
+        );
+        capture.record(
+            Direction::Outbound,
+            Some(2),
+            1,
+            "10.0.0.1:1000".parse().unwrap(),
+            "10.0.0.2:2000".parse().unwrap(),
+            b"second",
+        );
+        drop(capture);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let packets = read_udp_packets(&bytes).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].source, "[::1]:1000".parse().unwrap());
+        assert_eq!(packets[0].payload, b"first");
+        assert_eq!(packets[1].destination, "10.0.0.2:2000".parse().unwrap());
+        assert_eq!(packets[1].payload, b"second");
+        assert!(packets[0].timestamp <= packets[1].timestamp);
+    }
+}
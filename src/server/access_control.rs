@@ -0,0 +1,227 @@
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{error, info};
+
+/// Interval between the checks for changes of the list files
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Network given in CIDR notation, a plain address being a network of its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid network {}: {}", s, e))?
+            .to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            bits
+        } else {
+            prefix
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or(format!("Invalid prefix length in {}", s))?
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Networks listed in a file, one per line with `#` starting comments, reloaded when modified
+struct AccessList {
+    path: Option<String>,
+    modified: Option<SystemTime>,
+    networks: Vec<Cidr>,
+}
+
+impl AccessList {
+    fn new(path: Option<String>) -> Self {
+        let mut list = AccessList {
+            path,
+            modified: None,
+            networks: vec![],
+        };
+        list.reload();
+        list
+    }
+
+    /// Reads the file again when its modification time changed, returns whether it did
+    fn reload(&mut self) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+        match modified {
+            Ok(modified) if self.modified != Some(modified) => match fs::read_to_string(path) {
+                Ok(content) => {
+                    self.networks = parse(&content);
+                    self.modified = Some(modified);
+                    info!("Loaded {} networks from {}", self.networks.len(), path);
+                    true
+                }
+                Err(e) => {
+                    error!("Can't read access list {}: {}", path, e);
+                    false
+                }
+            },
+            Ok(_) => false,
+            Err(e) => {
+                // The last networks loaded stay in force, not to open the server by mistake
+                if self.modified.take().is_some() {
+                    error!("Can't access list {}: {}", path, e);
+                }
+                false
+            }
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Allow and deny lists of the networks clients can handshake from. Denied networks take
+/// precedence, and only allowed ones are accepted when an allow list is given.
+pub struct AccessControl {
+    allow: AccessList,
+    deny: AccessList,
+    checked: Instant,
+}
+
+impl AccessControl {
+    pub fn new(allow: Option<String>, deny: Option<String>) -> Self {
+        AccessControl {
+            allow: AccessList::new(allow),
+            deny: AccessList::new(deny),
+            checked: Instant::now(),
+        }
+    }
+
+    /// Reloads the lists whose files changed, at most once per interval, returns whether any did
+    pub fn reload(&mut self) -> bool {
+        if self.checked.elapsed() < RELOAD_INTERVAL {
+            return false;
+        }
+        self.checked = Instant::now();
+        let allow = self.allow.reload();
+        let deny = self.deny.reload();
+        allow || deny
+    }
+
+    /// Whether a client is accepted given the source identity of its image, that is its
+    /// socket address, which may not be an ip one on other transports
+    pub fn allows(&self, source_identity: &str) -> bool {
+        match source_ip(source_identity) {
+            Some(ip) => {
+                !self.deny.contains(&ip) && (!self.allow.is_enabled() || self.allow.contains(&ip))
+            }
+            None => !self.allow.is_enabled(),
+        }
+    }
+}
+
+pub fn source_ip(source_identity: &str) -> Option<IpAddr> {
+    let (host, _) = source_identity.rsplit_once(':')?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+fn parse(content: &str) -> Vec<Cidr> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            line.parse()
+                .map_err(|e| error!("Ignoring access list entry: {}", e))
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let network: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(network.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!network.contains(&"10.2.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&"192.168.1.1".parse().unwrap()));
+        assert!("fd00::/8"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&"fd12::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_access_control_reload() {
+        let directory = std::env::temp_dir();
+        let allow = directory.join(format!("allow-{}", std::process::id()));
+        let deny = directory.join(format!("deny-{}", std::process::id()));
+        fs::write(&allow, "# office\n10.0.0.0/8\n127.0.0.1\n").unwrap();
+        fs::write(&deny, "10.6.6.0/24 # abusive\n").unwrap();
+        let mut access = AccessControl::new(
+            Some(allow.to_str().unwrap().to_owned()),
+            Some(deny.to_str().unwrap().to_owned()),
+        );
+        assert!(access.allows("10.1.1.1:40000"));
+        assert!(access.allows("127.0.0.1:40000"));
+        assert!(!access.allows("10.6.6.6:40000"));
+        assert!(!access.allows("192.168.1.1:40000"));
+        assert!(!access.allows("[::1]:40000"));
+        assert!(!access.allows("aeron:ipc"));
+
+        fs::write(&allow, "192.168.1.0/24\n").unwrap();
+        let file = fs::File::options().write(true).open(&allow).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        access.checked -= RELOAD_INTERVAL;
+        assert!(access.reload());
+        assert!(access.allows("192.168.1.1:40000"));
+        assert!(!access.allows("10.1.1.1:40000"));
+
+        fs::remove_file(&allow).unwrap();
+        fs::remove_file(&deny).unwrap();
+    }
+}
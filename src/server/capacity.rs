@@ -0,0 +1,125 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{error, info};
+
+/// Interval between the checks for changes of the capacity file
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum number of simultaneously connected clients, which can be changed at runtime by
+/// writing it to a file, reloaded when modified. Clients beyond a lowered maximum keep their
+/// slots, new ones being turned away until enough are gone.
+pub struct Capacity {
+    max_clients: usize,
+    path: Option<String>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl Capacity {
+    pub fn new(max_clients: usize, path: Option<String>) -> Self {
+        let mut capacity = Capacity {
+            max_clients,
+            path,
+            modified: None,
+            checked: Instant::now(),
+        };
+        capacity.load();
+        capacity
+    }
+
+    pub fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+
+    /// Reads the file again when modified, at most once per interval, returns whether the
+    /// maximum changed
+    pub fn reload(&mut self) -> bool {
+        if self.checked.elapsed() < RELOAD_INTERVAL {
+            return false;
+        }
+        self.checked = Instant::now();
+        self.load()
+    }
+
+    fn load(&mut self) -> bool {
+        let Some(path) = &self.path else {
+            return false;
+        };
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+        match modified {
+            Ok(modified) if self.modified != Some(modified) => {
+                self.modified = Some(modified);
+                match fs::read_to_string(path).map(|content| content.trim().parse::<usize>()) {
+                    Ok(Ok(max_clients)) if max_clients != self.max_clients => {
+                        info!(
+                            "Maximum number of clients changed from {} to {}",
+                            self.max_clients, max_clients
+                        );
+                        self.max_clients = max_clients;
+                        true
+                    }
+                    Ok(Ok(_)) => false,
+                    Ok(Err(e)) => {
+                        error!(
+                            "Ignoring invalid maximum number of clients in {}: {}",
+                            path, e
+                        );
+                        false
+                    }
+                    Err(e) => {
+                        error!("Can't read capacity file {}: {}", path, e);
+                        false
+                    }
+                }
+            }
+            Ok(_) => false,
+            Err(e) => {
+                // The last maximum loaded stays in force
+                if self.modified.take().is_some() {
+                    error!("Can't access capacity file {}: {}", path, e);
+                }
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_capacity_reload() {
+        let path = std::env::temp_dir().join(format!("capacity-{}", std::process::id()));
+        fs::write(&path, "4\n").unwrap();
+        let mut capacity = Capacity::new(10, Some(path.to_string_lossy().into_owned()));
+        assert_eq!(capacity.max_clients(), 4);
+
+        fs::write(&path, "not a number").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert!(!capacity.load());
+        assert_eq!(capacity.max_clients(), 4);
+
+        fs::write(&path, "2").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(2))
+            .unwrap();
+        assert!(capacity.load());
+        assert_eq!(capacity.max_clients(), 2);
+
+        fs::remove_file(&path).unwrap();
+        assert!(!capacity.load());
+        assert_eq!(capacity.max_clients(), 2);
+        assert_eq!(Capacity::new(10, None).max_clients(), 10);
+    }
+}
@@ -1,75 +1,135 @@
-use crate::aeron::publisher::Publisher;
-use crate::aeron::subscriber::Subscriber;
-use crate::aeron::{instance_publisher, instance_subscriber, subscriber, Settings};
-use aeron_rs::image::Image;
-use aeron_rs::publication::Publication;
-use aeron_rs::subscription::Subscription;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
-
-use log::debug;
-
-use super::{CONNECTED_SESSIONS, DISCONNECTED_SESSIONS};
-use crate::Timeout;
-use aeron_rs::concurrent::atomic_buffer::AtomicBuffer;
-use aeron_rs::concurrent::logbuffer::header::Header;
-use aeron_rs::utils::types::Index;
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{debug, field, info_span, Span};
+
+use crate::compression::Compressor;
+use crate::deadline::Deadline;
+use crate::fec::Fec;
+use crate::latency::Pinger;
+use crate::messages::{Datagram, Frame};
+use crate::multipath::{self, Multipath};
+use crate::rate_limit::Shaper;
+use crate::transport::{FragmentHeader, ImageEvent, Publication, Subscription};
 use std::ops::Add;
 
 #[derive(Clone)]
 pub struct ClientSlot {
+    identity: String,
     stream_id: i32,
     publisher_session_id: i32,
     subscriber_session_id: Arc<Mutex<i32>>,
-    #[allow(dead_code)]
+    subscriber_session_ids: Arc<Mutex<HashSet<i32>>>,
+    source_identity: Arc<Mutex<Option<String>>>,
     port: usize,
-    #[allow(dead_code)]
     control: usize,
-    publisher: Arc<Publisher>,
-    publication: Arc<Mutex<Publication>>,
-    subscriber: Arc<Subscriber>,
-    subscription: Arc<Mutex<Subscription>>,
+    publication: Rc<dyn Publication>,
+    subscription: Rc<dyn Subscription>,
     timeout: Arc<Mutex<SystemTime>>,
+    session_timeout: Duration,
+    heartbeat_interval: Option<Duration>,
+    last_sent: Arc<Mutex<Instant>>,
+    pinger: Option<Arc<Mutex<Pinger>>>,
     closed: Arc<Mutex<bool>>,
+    throttled: Arc<Mutex<bool>>,
+    compressor: Compressor,
+    deadline: Deadline,
+    multipath: Option<Arc<Multipath>>,
+    fec: Arc<Fec>,
+    up: Arc<Mutex<Shaper>>,
+    down: Arc<Mutex<Shaper>>,
+    span: Span,
 }
 
 impl ClientSlot {
-    #[allow(clippy::arc_with_non_send_sync)]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        settings: &Settings,
-        channel_forward: String,
-        channel_backward: String,
+        publication: Rc<dyn Publication>,
+        subscription: Box<dyn Subscription>,
+        identity: String,
+        position: usize,
         port: usize,
         control: usize,
+        connection_timeout: Duration,
+        session_timeout: Duration,
+        heartbeat_interval: Option<Duration>,
+        ping_interval: Option<Duration>,
+        compressor: Compressor,
+        deadline: Deadline,
+        multipath: bool,
+        fec: Fec,
+        up: Shaper,
+        down: Shaper,
     ) -> Self {
-        let mut subscriber_context = Subscriber::new_context(settings);
-        subscriber_context.set_unavailable_image_handler(on_unavailable_image);
-        subscriber_context.set_available_image_handler(on_available_image);
-        let (client_subscriber, client_subscription) =
-            instance_subscriber(subscriber_context, settings, &channel_forward);
-
-        let publisher_context = Publisher::new_context(settings);
-        let (client_publisher, client_publication) =
-            instance_publisher(publisher_context, settings, &channel_backward);
-        let stream_id = client_publication.lock().unwrap().stream_id();
+        // Outliving the handshake it is created in, the slot is not part of it
+        let span = info_span!(
+            parent: None,
+            "slot",
+            slot = position + 1,
+            session_id = field::Empty,
+            stream_id = publication.stream_id(),
+            client = %identity,
+            address = field::Empty,
+        );
         ClientSlot {
-            stream_id,
+            identity,
+            stream_id: publication.stream_id(),
             subscriber_session_id: Arc::new(Mutex::new(-1)),
-            publisher_session_id: client_publication.clone().lock().unwrap().session_id(),
+            subscriber_session_ids: Arc::new(Mutex::new(HashSet::new())),
+            source_identity: Arc::new(Mutex::new(None)),
+            publisher_session_id: publication.session_id(),
             port,
             control,
-            publisher: Arc::new(client_publisher),
-            publication: client_publication,
-            subscriber: Arc::new(client_subscriber),
-            subscription: client_subscription,
-            timeout: Arc::new(Mutex::new(
-                SystemTime::now().add(Duration::from_secs(Timeout::CONNECTION_SECONDS)),
-            )),
+            publication,
+            subscription: Rc::from(subscription),
+            timeout: Arc::new(Mutex::new(SystemTime::now().add(connection_timeout))),
+            session_timeout,
+            heartbeat_interval,
+            last_sent: Arc::new(Mutex::new(Instant::now())),
+            pinger: ping_interval.map(|interval| Arc::new(Mutex::new(Pinger::new(interval)))),
             closed: Arc::new(Mutex::new(false)),
+            throttled: Arc::new(Mutex::new(false)),
+            compressor,
+            deadline,
+            multipath: multipath.then(|| Arc::new(Multipath::default())),
+            fec: Arc::new(fec),
+            up: Arc::new(Mutex::new(up)),
+            down: Arc::new(Mutex::new(down)),
+            span,
         }
     }
 
+    /// Name of the client the traffic of this slot is accounted to
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Span of the events concerning the slot, carrying its position, the session and address
+    /// of the client once connected
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Port the slot receives the traffic of the client on
+    pub fn port(&self) -> usize {
+        self.port
+    }
+
+    /// Control port the slot sends the traffic to the client from
+    pub fn control(&self) -> usize {
+        self.control
+    }
+
+    pub fn session_id(&self) -> i32 {
+        self.publisher_session_id
+    }
+
+    pub fn subscriber_session_id(&self) -> i32 {
+        *self.subscriber_session_id.lock().unwrap()
+    }
+
     pub fn is_publishing_on_session(&self, session_id: i32) -> bool {
         self.publisher_session_id == session_id
     }
@@ -77,65 +137,160 @@ impl ClientSlot {
     pub fn has_subscribers_on_session(&self, session_id: i32) -> bool {
         *self.subscriber_session_id.lock().unwrap() == session_id
             || self
-                .subscription
+                .subscriber_session_ids
                 .lock()
                 .unwrap()
-                .image_by_session_id(session_id)
-                .is_some()
+                .contains(&session_id)
+            || self.subscription.has_image(session_id)
     }
 
-    pub fn activate(&self, session_id: i32) {
+    pub fn activate(&self, session_id: i32, source_identity: &str) {
         *self.subscriber_session_id.lock().unwrap() = session_id;
-        *self.timeout.lock().unwrap() = SystemTime::now()
-            .checked_add(Duration::from_secs(Timeout::SESSION_SECONDS))
-            .unwrap();
+        self.subscriber_session_ids
+            .lock()
+            .unwrap()
+            .insert(session_id);
+        *self.source_identity.lock().unwrap() = Some(source_identity.to_owned());
+        self.span.record("session_id", session_id);
+        self.span.record("address", source_identity);
+        self.refresh_timeout();
+    }
+
+    /// Forgets a closed session of the client, returns whether it was the last one, clients
+    /// sending over several paths having one per path
+    pub fn deactivate(&self, session_id: i32) -> bool {
+        let mut session_ids = self.subscriber_session_ids.lock().unwrap();
+        session_ids.remove(&session_id);
+        session_ids.is_empty()
+    }
+
+    /// Address the client publishes from, once it connected to the slot
+    pub fn source_identity(&self) -> Option<String> {
+        self.source_identity.lock().unwrap().clone()
     }
 
     pub fn is_timeout_elapsed(&self) -> bool {
         *self.timeout.lock().unwrap() <= SystemTime::now()
     }
 
-    pub fn publish(&self, slice_msg: &mut [u8], slice_size: usize, origin: SocketAddr) {
-        *self.timeout.lock().unwrap() =
-            SystemTime::now().add(Duration::from_secs(Timeout::SESSION_SECONDS));
+    pub fn publish(&self, datagram: &Datagram) {
+        // With heartbeats, only what is received from the client proves it alive
+        if self.heartbeat_interval.is_none() {
+            self.refresh_timeout();
+        }
         debug!(
-            "Publishing on stream {} from session {} {} bytes received from endpoint {:?}",
-            self.stream_id, self.publisher_session_id, slice_size, origin
+            "Publishing on stream {} from session {} {} bytes received from {:?}",
+            self.stream_id,
+            self.publisher_session_id,
+            datagram.payload.len(),
+            datagram.metadata
+        );
+        let frame = multipath::frame(
+            self.compressor.compress(datagram),
+            &self.deadline,
+            self.multipath.as_deref(),
         );
-        self.publisher
-            .send(self.publication.to_owned(), slice_msg, slice_size)
+        self.fec
+            .encode(frame)
+            .iter()
+            .for_each(|frame| self.send(frame));
+    }
+
+    /// Sends the parity shards of a group of datagrams left partially filled for a while
+    pub fn flush(&self) {
+        self.fec.flush().iter().for_each(|frame| self.send(frame));
+    }
+
+    /// Sends a heartbeat when heartbeats were negotiated and nothing was sent for an interval
+    pub fn heartbeat(&self) {
+        let idle = self
+            .heartbeat_interval
+            .is_some_and(|interval| self.last_sent.lock().unwrap().elapsed() >= interval);
+        if idle {
+            self.send(&Frame::Heartbeat);
+        }
+    }
+
+    /// Sends a ping when pings were negotiated and one is due
+    pub fn ping(&self) {
+        let ping = self
+            .pinger
+            .as_ref()
+            .and_then(|pinger| pinger.lock().unwrap().ping());
+        if let Some(ping) = ping {
+            self.send(&ping);
+        }
+    }
+
+    /// Round trip times measured by the pings sent to the client
+    pub fn pinger(&self) -> Option<MutexGuard<'_, Pinger>> {
+        self.pinger.as_ref().map(|pinger| pinger.lock().unwrap())
+    }
+
+    pub fn send(&self, frame: &Frame) {
+        *self.last_sent.lock().unwrap() = Instant::now();
+        self.publication
+            .publish(&bincode::serialize(frame).unwrap())
+    }
+
+    pub fn receive(&self, on_new_fragment: &mut dyn FnMut(&[u8], &FragmentHeader)) {
+        if self.subscription.poll(on_new_fragment) > 0 {
+            self.refresh_timeout();
+        }
     }
 
-    pub fn receive<F>(&self, on_new_fragment: F)
-    where
-        F: Fn(&AtomicBuffer, Index, Index, &Header),
-    {
-        *self.timeout.lock().unwrap() =
-            SystemTime::now().add(Duration::from_secs(Timeout::SESSION_SECONDS));
-        self.subscriber
-            .recv(self.subscription.to_owned(), on_new_fragment);
+    /// Drains the connections and disconnections of client publications to this slot
+    pub fn image_events(&self) -> Vec<ImageEvent> {
+        self.subscription.image_events()
     }
 
     pub fn close(&self) {
         *self.closed.lock().unwrap() = true;
-        self.publication.lock().unwrap().close();
-        self.subscription.lock().unwrap().close_and_remove_images();
+        self.publication.close();
+        self.subscription.close();
     }
 
     pub fn is_closed(&self) -> bool {
         *self.closed.lock().unwrap()
     }
-}
 
-fn on_unavailable_image(image: &Image) {
-    subscriber::unavailable_image_handler(image);
-    DISCONNECTED_SESSIONS
-        .lock()
-        .unwrap()
-        .push(image.session_id());
-}
+    pub fn is_throttled(&self) -> bool {
+        *self.throttled.lock().unwrap()
+    }
+
+    pub fn set_throttled(&self, throttled: bool) {
+        *self.throttled.lock().unwrap() = throttled;
+    }
 
-fn on_available_image(image: &Image) {
-    subscriber::available_image_handler(image);
-    CONNECTED_SESSIONS.lock().unwrap().push(image.session_id());
+    pub fn compressor(&self) -> &Compressor {
+        &self.compressor
+    }
+
+    pub fn deadline(&self) -> &Deadline {
+        &self.deadline
+    }
+
+    /// Sequences of the datagrams exchanged with a client sending over several paths
+    pub fn multipath(&self) -> Option<&Multipath> {
+        self.multipath.as_deref()
+    }
+
+    /// Forward error correction of the datagrams exchanged with the client
+    pub fn fec(&self) -> &Fec {
+        &self.fec
+    }
+
+    /// Rate limits of the traffic received from the client
+    pub fn up(&self) -> MutexGuard<'_, Shaper> {
+        self.up.lock().unwrap()
+    }
+
+    /// Rate limits of the traffic published to the client
+    pub fn down(&self) -> MutexGuard<'_, Shaper> {
+        self.down.lock().unwrap()
+    }
+
+    fn refresh_timeout(&self) {
+        *self.timeout.lock().unwrap() = SystemTime::now().add(self.session_timeout);
+    }
 }
@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, info};
+
+struct LearnedPeer {
+    slots: HashSet<usize>,
+    last_seen: SystemTime,
+}
+
+/// Remote applications that sent datagrams to the endpoint socket when the server binds it
+/// instead of connecting, along with the slots their traffic has been published to. Peers are
+/// forgotten after the session timeout without sending.
+pub struct EndpointPeers {
+    accept: Vec<IpAddr>,
+    peers: HashMap<SocketAddr, LearnedPeer>,
+    session_timeout: Duration,
+}
+
+impl EndpointPeers {
+    pub fn new(accept: &[IpAddr], session_timeout: Duration) -> Self {
+        EndpointPeers {
+            accept: accept.to_vec(),
+            peers: HashMap::new(),
+            session_timeout,
+        }
+    }
+
+    pub fn accepts(&self, addr: &SocketAddr) -> bool {
+        self.accept.is_empty() || self.accept.contains(&addr.ip())
+    }
+
+    /// Records a datagram received from a peer, which is not tied to any slot until its traffic
+    /// is published to one
+    pub fn learn(&mut self, addr: SocketAddr) {
+        let peer = self.peers.entry(addr).or_insert_with(|| {
+            info!("Learned endpoint peer {}", addr);
+            LearnedPeer {
+                slots: HashSet::new(),
+                last_seen: SystemTime::now(),
+            }
+        });
+        peer.last_seen = SystemTime::now();
+    }
+
+    /// Ties a learned peer to the slot its traffic was published to, so that the traffic of the
+    /// client of the slot goes back to it. Unknown peers are left alone.
+    pub fn published(&mut self, addr: SocketAddr, index: usize) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.slots.insert(index);
+        }
+    }
+
+    /// Peers whose traffic was published to the slot at the given index, none when no peer
+    /// spoke to its client yet
+    pub fn peers_of_slot(&self, index: usize) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.slots.contains(&index))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    pub fn release_slot(&mut self, index: usize) {
+        self.peers.values_mut().for_each(|peer| {
+            peer.slots.remove(&index);
+        });
+    }
+
+    pub fn expire(&mut self) {
+        let session_timeout = self.session_timeout;
+        self.peers.retain(|addr, peer| {
+            let alive = peer
+                .last_seen
+                .elapsed()
+                .map(|elapsed| elapsed < session_timeout)
+                .unwrap_or(true);
+            if !alive {
+                debug!("Forgetting endpoint peer {} after inactivity", addr);
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_peers_of_slot() {
+        let mut peers = EndpointPeers::new(&[], Duration::from_secs(5));
+        let first = SocketAddr::from_str("10.0.0.1:5000").unwrap();
+        let second = SocketAddr::from_str("10.0.0.2:5000").unwrap();
+        peers.learn(first);
+        peers.learn(second);
+        // Learned peers are tied to no slot until published to it
+        assert!(peers.peers_of_slot(0).is_empty());
+        peers.published(first, 0);
+        peers.published(second, 1);
+        peers.published(SocketAddr::from_str("10.0.0.3:5000").unwrap(), 1);
+        assert_eq!(peers.peers_of_slot(0), vec![first]);
+        assert_eq!(peers.peers_of_slot(1), vec![second]);
+
+        // The traffic of a slot never goes to the peers of the others
+        peers.release_slot(0);
+        assert!(peers.peers_of_slot(0).is_empty());
+    }
+
+    #[test]
+    fn test_accepts() {
+        let peers = EndpointPeers::new(
+            &[IpAddr::from_str("10.0.0.1").unwrap()],
+            Duration::from_secs(5),
+        );
+        assert!(peers.accepts(&SocketAddr::from_str("10.0.0.1:5000").unwrap()));
+        assert!(!peers.accepts(&SocketAddr::from_str("10.0.0.2:5000").unwrap()));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut peers = EndpointPeers::new(&[], Duration::from_millis(50));
+        let peer = SocketAddr::from_str("10.0.0.1:5000").unwrap();
+        peers.learn(peer);
+        peers.published(peer, 0);
+        peers.expire();
+        assert_eq!(peers.peers_of_slot(0), vec![peer]);
+        std::thread::sleep(Duration::from_millis(60));
+        peers.expire();
+        assert!(peers.peers_of_slot(0).is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+
+use tracing::{error, info};
+
+use crate::server::access_control::{source_ip, Cidr};
+
+/// Identities the traffic of clients is accounted to, decided by the server from the source
+/// address of their handshake rather than by the name they declare, which anyone could claim.
+/// Networks are mapped to identities in a file, one `NETWORK IDENTITY` pair per line with `#`
+/// starting comments, the first listed network containing the source winning.
+pub struct Identities {
+    networks: Vec<(Cidr, String)>,
+}
+
+impl Identities {
+    pub fn new(path: Option<String>) -> Self {
+        let networks = match &path {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .unwrap_or_else(|e| panic!("Cannot read identities {}: {}", path, e));
+                let networks = parse(&content);
+                info!("Loaded {} identities from {}", networks.len(), path);
+                networks
+            }
+            None => vec![],
+        };
+        Identities { networks }
+    }
+
+    /// Identity of a client given the source identity of its handshake image and the name it
+    /// declared. Clients outside the mapped networks are accounted to their name qualified by
+    /// their source ip, so that they can't spend the quotas of the other hosts.
+    pub fn identify(&self, source_identity: &str, declared: &str) -> String {
+        let name = match declared.split_whitespace().collect::<Vec<&str>>() {
+            words if words.is_empty() => String::from("anonymous"),
+            words => words.join("_"),
+        };
+        match source_ip(source_identity) {
+            Some(ip) => self
+                .networks
+                .iter()
+                .find(|(network, _)| network.contains(&ip))
+                .map(|(_, identity)| identity.clone())
+                .unwrap_or_else(|| format!("{}@{}", name, ip)),
+            None => name,
+        }
+    }
+}
+
+fn parse(content: &str) -> Vec<(Cidr, String)> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [network, identity] => network
+                    .parse()
+                    .map(|network| (network, identity.to_owned()))
+                    .map_err(|e| error!("Ignoring identities entry: {}", e))
+                    .ok(),
+                _ => {
+                    error!("Ignoring malformed identities entry: {}", line);
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identify() {
+        let identities = Identities {
+            networks: parse("# offices\n10.1.0.0/16 team-a\n10.0.0.0/8 team-b\nbogus\n"),
+        };
+        assert_eq!(identities.identify("10.1.2.3:40000", "laptop"), "team-a");
+        assert_eq!(identities.identify("10.2.2.3:40000", "team-a"), "team-b");
+        assert_eq!(
+            identities.identify("192.168.1.1:40000", "team a"),
+            "team_a@192.168.1.1"
+        );
+        assert_eq!(identities.identify("[::1]:40000", ""), "anonymous@::1");
+        assert_eq!(identities.identify("aeron:ipc", "team-a"), "team-a");
+    }
+}
@@ -1,154 +1,375 @@
+mod access_control;
+mod capacity;
 mod client_slot;
+mod endpoint_peers;
+mod identities;
+mod slot_manager;
 
-use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::Add;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::{io, slice};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use aeron_rs::concurrent::atomic_buffer::AtomicBuffer;
-use aeron_rs::concurrent::logbuffer::header::Header;
-use aeron_rs::utils::types::Index;
-use log::{debug, error, info};
+use tracing::{debug, error, info, info_span, warn};
 
-use crate::aeron::publisher::Publisher;
-use crate::aeron::subscriber::Subscriber;
-use crate::aeron::{instance_publisher, instance_subscriber, Settings};
-use crate::{attach_endpoint, Arguments};
+use crate::accounting::{Accounting, QuotaAction};
+use crate::aeron::transport::AeronTransport;
+use crate::aeron::Settings;
+use crate::{attach_endpoint, endpoint_packet, ipc_channel, Arguments, Timeout};
 
-use crate::messages::{Failure, FailureDetails, HandshakeRequest, HandshakeResponse};
+use crate::capture::{Capture, Direction};
+use crate::compression::{decompress, Compressor};
+use crate::deadline::Deadline;
+use crate::fec::Fec;
+use crate::logging::Throttle;
+use crate::messages::{
+    Capabilities, Compression, Datagram, Failure, FailureDetails, Frame, HandshakeReply,
+    HandshakeRequest, HandshakeResponse, Origin, Reliability, RequestPreamble,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::rate_limit::{RateLimiter, Shaper};
+use crate::server::access_control::AccessControl;
+use crate::server::capacity::Capacity;
 use crate::server::client_slot::ClientSlot;
+use crate::server::endpoint_peers::EndpointPeers;
+use crate::server::identities::Identities;
+use crate::server::slot_manager::SlotManager;
+pub use crate::server::slot_manager::SlotPolicy;
+use crate::systemd::Notifier;
+use crate::transport::{FragmentHeader, ImageEvent, Publication, Subscription, Transport};
 use std::str::FromStr;
 
-lazy_static! {
-    pub static ref CONNECTED_SESSIONS: Mutex<Vec<i32>> = Mutex::new(vec!());
-    pub static ref DISCONNECTED_SESSIONS: Mutex<Vec<i32>> = Mutex::new(vec!());
-}
-
 pub struct Server {
     settings: Settings,
     channel_forward: String,
     channel_backward: String,
     args: Arguments,
+    /// Slots allocated on demand, trailing free ones being dropped
     slots: RefCell<Vec<Option<ClientSlot>>>,
+    capacity: RefCell<Capacity>,
+    slot_manager: RefCell<SlotManager>,
+    peers: RefCell<EndpointPeers>,
+    capture: Option<Capture>,
+    transport: Box<dyn Transport>,
+    global_up: RefCell<RateLimiter>,
+    global_down: RefCell<RateLimiter>,
+    accounting: RefCell<Accounting>,
+    access: RefCell<AccessControl>,
+    identities: Identities,
+    handshake_sources: RefCell<HashMap<i32, String>>,
+    /// Response publications to the clients of a single port server, by handshake session, until
+    /// handed over to their slots
+    responses: RefCell<HashMap<i32, Rc<dyn Publication>>>,
+    redirects: Cell<usize>,
+    notifier: Notifier,
+    send_errors: Throttle,
+    receive_errors: Throttle,
 }
 
 impl Server {
     pub fn instance(args: &Arguments) -> Self {
+        let settings = Settings::new(args);
+        let transport = AeronTransport::new(&settings);
+        Server::new(args, settings, Box::new(transport))
+    }
+
+    pub fn new(args: &Arguments, settings: Settings, transport: Box<dyn Transport>) -> Self {
+        let (channel_forward, channel_backward) = if args.ipc {
+            (ipc_channel(args.port), ipc_channel(args.control))
+        } else {
+            (
+                format!("aeron:udp?{}", args.sforward),
+                format!("aeron:udp?{}", args.sbackward),
+            )
+        };
+        let peers = EndpointPeers::new(&args.accept, settings.session_timeout);
         Server {
-            settings: Settings::new(args),
-            channel_forward: format!("aeron:udp?{}", args.sforward),
-            channel_backward: format!("aeron:udp?{}", args.sbackward),
+            settings,
+            channel_forward,
+            channel_backward,
             args: args.clone(),
-            slots: RefCell::new(vec![None; args.max_clients]),
+            slots: RefCell::new(vec![]),
+            slot_manager: RefCell::new(SlotManager::new(args.slot_policy)),
+            capacity: RefCell::new(Capacity::new(
+                args.max_clients,
+                args.max_clients_file.clone(),
+            )),
+            peers: RefCell::new(peers),
+            capture: args.capture.as_ref().map(|path| Capture::create(path)),
+            transport,
+            global_up: RefCell::new(RateLimiter::new(&args.global_limit_up)),
+            global_down: RefCell::new(RateLimiter::new(&args.global_limit_down)),
+            accounting: RefCell::new(Accounting::new(
+                args.accounting.clone(),
+                args.daily_quota,
+                args.monthly_quota,
+            )),
+            access: RefCell::new(AccessControl::new(args.allow.clone(), args.deny.clone())),
+            identities: Identities::new(args.identities.clone()),
+            handshake_sources: RefCell::new(HashMap::new()),
+            responses: RefCell::new(HashMap::new()),
+            redirects: Cell::new(0),
+            notifier: Notifier::from_env(),
+            send_errors: Throttle::default(),
+            receive_errors: Throttle::default(),
+        }
+    }
+
+    /// Maximum number of simultaneously connected clients currently in force
+    fn max_clients(&self) -> usize {
+        self.capacity.borrow().max_clients()
+    }
+
+    /// Whether the drain file exists, new clients being turned away meanwhile
+    fn is_draining(&self) -> bool {
+        self.args
+            .drain_file
+            .as_ref()
+            .is_some_and(|path| Path::new(path).exists())
+    }
+
+    /// Server of the pool to point a client at when turned away, in turn, if the client follows
+    /// redirects
+    fn redirect(&self, request: &HandshakeRequest) -> Option<IpAddr> {
+        if self.args.redirect.is_empty() || !request.capabilities.contains(Capabilities::REDIRECT) {
+            return None;
         }
+        let next = self.redirects.get();
+        self.redirects.set(next.wrapping_add(1));
+        Some(self.args.redirect[next % self.args.redirect.len()])
     }
 
     pub fn start(&self, running: Arc<AtomicBool>) {
         let socket = attach_endpoint(&self.args);
 
-        let (subscriber, subscription) = instance_subscriber(
-            Subscriber::new_context(&self.settings),
-            &self.settings,
-            &self.channel_forward,
-        );
-        let (publisher, publication) = instance_publisher(
-            Publisher::new_context(&self.settings),
-            &self.settings,
-            &self.channel_backward,
-        );
+        let subscription = self.transport.subscription(&self.channel_forward);
+        // Single port servers answer each client on its own response channel instead
+        let publication: Option<Rc<dyn Publication>> = (!self.args.single_port)
+            .then(|| Rc::from(self.transport.publication(&self.channel_backward)));
 
-        let on_client_handshake =
-            |buffer: &AtomicBuffer, offset: Index, length: Index, header: &Header| {
-                debug!(
-                    "Received handshake request from session_id={} stream_id={} (length={})",
-                    header.session_id(),
-                    header.stream_id(),
-                    length
+        let mut on_client_handshake = |buffer: &[u8], header: &FragmentHeader| {
+            let _span = info_span!("handshake", session_id = header.session_id).entered();
+            debug!(
+                "Received handshake request from session_id={} stream_id={} (length={})",
+                header.session_id,
+                header.stream_id,
+                buffer.len()
+            );
+            self.track_handshake_sources(subscription.as_ref());
+            // Clients of a single port server keep publishing on their handshake session
+            if self.args.single_port
+                && self
+                    .slots
+                    .borrow()
+                    .iter()
+                    .flatten()
+                    .any(|slot| slot.has_subscribers_on_session(header.session_id))
+            {
+                return;
+            }
+            let Some(reply) = publication
+                .clone()
+                .or_else(|| self.responses.borrow().get(&header.session_id).cloned())
+            else {
+                error!(
+                    "Ignoring handshake request without response channel from session_id={}",
+                    header.session_id
                 );
-                let request = unsafe {
-                    let slice_msg = slice::from_raw_parts_mut(
-                        buffer.buffer().offset(offset as isize),
-                        length as usize,
-                    );
-                    bincode::deserialize(slice_msg).unwrap()
-                };
+                return;
+            };
+            let Some(preamble) = RequestPreamble::decode(buffer) else {
+                error!(
+                    "Ignoring handshake request of unknown protocol from session_id={}",
+                    header.session_id
+                );
+                return;
+            };
 
-                let failure_details = FailureDetails {
-                    session_id: header.session_id(),
+            // Failures carry the verification too, so that clients can tell which are theirs
+            let failure_details = FailureDetails {
+                session_id: header.session_id.wrapping_mul(preamble.key),
+            };
+            let result = if !preamble.is_compatible() {
+                info!(
+                    "Rejecting handshake of protocol version {}, supported {}-{}",
+                    preamble.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                );
+                Err(Failure::HandshakeFailedIncompatibleVersion(
+                    failure_details,
+                    PROTOCOL_VERSION,
+                ))
+            } else {
+                let request: HandshakeRequest = match bincode::deserialize(buffer) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        error!("Discarding malformed handshake request: {}", e);
+                        return;
+                    }
                 };
+                // Draining servers keep their clients, but take no new one
                 let position = self
                     .slots
                     .borrow()
                     .iter()
                     .position(|s| {
-                        s.is_some()
-                            && s.clone()
-                                .unwrap()
-                                .is_publishing_on_session(header.session_id())
+                        s.as_ref()
+                            .is_some_and(|s| s.is_publishing_on_session(header.session_id))
                     })
-                    .or_else(|| self.slots.borrow().iter().position(|s| s.is_none()));
-                let result = match position {
-                    Some(index) => self.handshake(header, request, failure_details, index),
-                    None => Err(Failure::HandshakeFailedServerFull(failure_details)),
-                };
-                let response = bincode::serialize(&result).unwrap();
-                debug!(
-                    "Sending handshake response (success={}, length={})",
-                    result.is_ok(),
-                    response.len()
-                );
-                publisher.send(publication.to_owned(), response.as_ref(), response.len());
+                    .or_else(|| {
+                        let occupied: Vec<bool> =
+                            self.slots.borrow().iter().map(|s| s.is_some()).collect();
+                        self.slot_manager
+                            .borrow()
+                            .allocate(
+                                &occupied,
+                                self.max_clients(),
+                                &self.client_identity(header.session_id, &request),
+                            )
+                            .filter(|_| !self.is_draining())
+                    });
+                match position {
+                    Some(index) => {
+                        self.handshake(header, request, failure_details, index, reply.clone())
+                    }
+                    None => match self.redirect(&request) {
+                        Some(server) => {
+                            info!(
+                                "Redirecting handshake to server {} since {}",
+                                server,
+                                if self.is_draining() {
+                                    "draining"
+                                } else {
+                                    "full"
+                                }
+                            );
+                            Err(Failure::HandshakeRedirect(failure_details, server))
+                        }
+                        None => Err(Failure::HandshakeFailedServerFull(failure_details)),
+                    },
+                }
             };
+            let response = bincode::serialize(&HandshakeReply::new(result)).unwrap();
+            debug!(
+                "Sending handshake response (success={}, length={})",
+                result.is_ok(),
+                response.len()
+            );
+            reply.publish(&response);
+        };
 
-        let on_subscriber_receive =
-            |buffer: &AtomicBuffer, offset: Index, length: Index, header: &Header| {
-                let peer_addr = socket
-                    .peer_addr()
-                    .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
+        let mut on_subscriber_receive = |buffer: &[u8], header: &FragmentHeader| {
+            let slot_index = self.slots.borrow().iter().position(|slot| {
+                slot.is_some()
+                    && slot
+                        .as_ref()
+                        .unwrap()
+                        .has_subscribers_on_session(header.session_id)
+            });
+            let slot = slot_index.and_then(|index| self.slots.borrow()[index].clone());
+            // Slots of a single port server may join the image of the client past its handshake
+            if self.args.single_port && RequestPreamble::decode(buffer).is_some() {
                 debug!(
-                    "Sending {} bytes received on stream {} from session {} to endpoint {}",
-                    length,
-                    header.stream_id(),
-                    header.session_id(),
-                    peer_addr.clone()
+                    "Ignoring handshake request from session {}",
+                    header.session_id
                 );
-                unsafe {
-                    let slice_msg = slice::from_raw_parts_mut(
-                        buffer.buffer().offset(offset as isize),
-                        length as usize,
+                return;
+            }
+            let frames = match bincode::deserialize::<Frame>(buffer) {
+                Ok(frame) => match &slot {
+                    Some(slot) => slot.fec().decode(frame),
+                    None => vec![frame],
+                },
+                Err(e) => {
+                    error!(
+                        "Discarding malformed datagram from session {}: {}",
+                        header.session_id, e
                     );
-                    if socket.peer_addr().is_ok() {
-                        socket.send(slice_msg).unwrap_or_else(|e| {
-                            error!("Can't send packets to endpoint: {}", e);
-                            0
-                        });
-                    }
-                    self.slots
-                        .borrow()
-                        .iter()
-                        .enumerate()
-                        .filter(|(_, slot)| {
-                            slot.is_some()
-                                && !slot
-                                    .as_ref()
-                                    .unwrap()
-                                    .has_subscribers_on_session(header.session_id())
-                        })
-                        .for_each(|(index, slot)| {
-                            debug!(
-                                "Forwarding {} bytes to subscriber on slot {}/{}",
-                                length,
-                                index + 1,
-                                self.args.max_clients
-                            );
-                            slot.as_ref()
-                                .unwrap()
-                                .publish(slice_msg, length as usize, peer_addr)
-                        });
+                    return;
                 }
             };
+            for frame in frames {
+                // Datagrams are normalized into their multipath sequence and deadline, if any
+                let carried = match frame {
+                    Frame::Datagram(datagram) => Some((None, None, datagram)),
+                    Frame::Expiring { deadline, datagram } => {
+                        Some((None, Some(deadline), datagram))
+                    }
+                    Frame::Sequenced {
+                        sequence,
+                        deadline,
+                        datagram,
+                    } => Some((Some(sequence), deadline, datagram)),
+                    Frame::Ping {
+                        sequence,
+                        timestamp,
+                    } => {
+                        if let Some(slot) = &slot {
+                            slot.send(&Frame::Pong {
+                                sequence,
+                                timestamp,
+                            });
+                        }
+                        None
+                    }
+                    Frame::Pong { timestamp, .. } => {
+                        if let Some(mut pinger) = slot.as_ref().and_then(|slot| slot.pinger()) {
+                            pinger.pong(timestamp);
+                        }
+                        None
+                    }
+                    // Heartbeats carry nothing, and shards within shards are never sent
+                    Frame::Heartbeat | Frame::FecData { .. } | Frame::FecParity { .. } => None,
+                };
+                let Some((sequence, deadline, datagram)) = carried else {
+                    debug!("Received control frame from session {}", header.session_id);
+                    continue;
+                };
+                let multipath = slot.as_ref().and_then(|slot| slot.multipath());
+                if sequence.is_some_and(|sequence| {
+                    multipath.is_some_and(|multipath| multipath.is_duplicate(sequence))
+                }) {
+                    debug!(
+                        "Dropping duplicate datagram from session {}",
+                        header.session_id
+                    );
+                    continue;
+                }
+                if deadline.is_some_and(|deadline| {
+                    slot.as_ref()
+                        .is_some_and(|slot| slot.deadline().is_stale(deadline))
+                }) {
+                    debug!("Dropping stale datagram from session {}", header.session_id);
+                    continue;
+                }
+                let datagram = match decompress(datagram) {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        error!(
+                            "Discarding malformed datagram from session {}: {}",
+                            header.session_id, e
+                        );
+                        continue;
+                    }
+                };
+                let datagram = match slot_index {
+                    Some(index) => {
+                        let slots = self.slots.borrow();
+                        let mut up = slots[index].as_ref().unwrap().up();
+                        up.shape(&mut self.global_up.borrow_mut(), datagram)
+                    }
+                    None => Some(datagram),
+                };
+                if let Some(datagram) = datagram {
+                    self.forward_from_client(&socket, slot_index, header.session_id, datagram);
+                }
+            }
+        };
 
         info!(
             "Server waiting for handshakes, {} to endpoint {}",
@@ -159,150 +380,1393 @@ impl Server {
             },
             self.args.endpoint
         );
+        self.notifier.ready("Waiting for handshakes");
 
+        let mut statistics_timeout =
+            SystemTime::now().add(Duration::from_secs(Timeout::STATISTICS_SECONDS));
         while running.load(Ordering::SeqCst) {
+            if statistics_timeout < SystemTime::now() {
+                statistics_timeout =
+                    statistics_timeout.add(Duration::from_secs(Timeout::STATISTICS_SECONDS));
+                self.log_statistics();
+            }
+
             let mut recv_buff = vec![0; self.settings.message_length as usize];
             match socket.recv_from(&mut recv_buff) {
                 Ok((n, addr)) => {
-                    self.slots
-                        .borrow()
-                        .iter()
-                        .filter(|slot| slot.is_some())
-                        .for_each(|slot| {
-                            slot.as_ref()
-                                .unwrap()
-                                .publish(recv_buff.as_mut_slice(), n, addr)
+                    if self.peers.borrow().accepts(&addr) {
+                        if socket.peer_addr().is_err() {
+                            self.peers.borrow_mut().learn(addr);
+                        }
+                        let datagram = Datagram::new(Origin::Server, addr, &recv_buff[..n]);
+                        let local_addr = socket
+                            .local_addr()
+                            .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
+                        let slots = self.slots.borrow();
+                        let active_slots =
+                            slots.iter().enumerate().filter(|(_, slot)| slot.is_some());
+                        active_slots.for_each(|(index, slot)| {
+                            self.publish_to_slot(
+                                index,
+                                slot.as_ref().unwrap(),
+                                datagram.clone(),
+                                local_addr,
+                            )
                         });
+                    } else {
+                        debug!(
+                            "Discarding {} bytes from unaccepted endpoint peer {}",
+                            n, addr
+                        );
+                    }
                 }
                 Err(err) => {
                     if err.kind() != io::ErrorKind::WouldBlock {
-                        error!("Error receiving from endpoint {:?}", err)
+                        if let Some(suppressed) = self.receive_errors.admit() {
+                            error!(suppressed, "Error receiving from endpoint {:?}", err)
+                        }
                     }
                 }
             }
 
+            self.release_deferred(&socket);
+            // Slots holding back delayed traffic are not polled, so that their clients slow down
             self.slots
                 .borrow()
                 .iter()
-                .filter(|slot| slot.is_some())
-                .for_each(|slot| slot.clone().unwrap().receive(on_subscriber_receive));
-
-            self.handle_disconnections();
+                .flatten()
+                .filter(|slot| !slot.up().has_deferred())
+                .for_each(|slot| {
+                    let _span = slot.span().enter();
+                    slot.receive(&mut on_subscriber_receive)
+                });
 
-            self.handle_connections();
-            subscriber.recv(subscription.to_owned(), on_client_handshake);
+            self.handle_image_events();
+            self.handle_timeouts();
+            self.handle_quotas();
+            self.handle_access();
+            self.capacity.borrow_mut().reload();
+            self.send_control_frames();
+            if let Some(capture) = &self.capture {
+                capture.flush_periodically();
+            }
+            self.track_handshake_sources(subscription.as_ref());
+            subscription.poll(&mut on_client_handshake);
+            let clients = self.slots.borrow().iter().flatten().count();
+            self.notifier.status(&format!(
+                "{} of {} clients connected",
+                clients,
+                self.max_clients()
+            ));
+            self.notifier.watchdog();
 
             std::thread::sleep(Duration::from_millis(1));
         }
+
+        self.notifier.stopping();
+        subscription.close();
+        if let Some(publication) = publication {
+            publication.close();
+        }
+        self.responses
+            .borrow_mut()
+            .drain()
+            .for_each(|(_, response)| response.close());
+        self.accounting.borrow_mut().save();
+        self.slots
+            .borrow_mut()
+            .iter_mut()
+            .filter_map(|slot| slot.take())
+            .for_each(|slot| slot.close());
     }
 
-    fn handle_connections(&self) {
-        if let Ok(mut guard) = CONNECTED_SESSIONS.try_lock() {
-            guard.drain(..).for_each(|session_id| {
-                let slots = self.slots.borrow();
-                if let Some((position, slot)) = slots.iter().enumerate().find(|(_, slot)| {
-                    slot.is_some()
-                        && slot
-                            .as_ref()
-                            .unwrap()
-                            .has_subscribers_on_session(session_id)
-                }) {
-                    slot.as_ref().unwrap().activate(session_id);
+    fn forward_from_client(
+        &self,
+        socket: &UdpSocket,
+        slot_index: Option<usize>,
+        session_id: i32,
+        datagram: Datagram,
+    ) {
+        if let Some(slot) = slot_index.and_then(|index| self.slots.borrow()[index].clone()) {
+            self.account(&slot, Direction::Inbound, &datagram);
+        }
+        let local_addr = socket
+            .local_addr()
+            .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
+        self.send_to_endpoint(socket, slot_index, session_id, &datagram, local_addr);
+        self.slots
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(index, slot)| slot.is_some() && slot_index != Some(*index))
+            .for_each(|(index, slot)| {
+                debug!(
+                    "Forwarding {} bytes to subscriber on slot {}/{}",
+                    datagram.payload.len(),
+                    index + 1,
+                    self.max_clients()
+                );
+                self.publish_to_slot(index, slot.as_ref().unwrap(), datagram.clone(), local_addr)
+            });
+    }
+
+    /// Sends the traffic of a client to the connected endpoint, or to the peers that sent to its
+    /// slot when the endpoint socket is bound
+    fn send_to_endpoint(
+        &self,
+        socket: &UdpSocket,
+        slot_index: Option<usize>,
+        session_id: i32,
+        datagram: &Datagram,
+        local_addr: SocketAddr,
+    ) {
+        let peer_addrs = match socket.peer_addr() {
+            Ok(peer_addr) => vec![peer_addr],
+            Err(_) => slot_index
+                .map(|index| self.peers.borrow().peers_of_slot(index))
+                .unwrap_or_default(),
+        };
+        if peer_addrs.is_empty() {
+            debug!(
+                "Dropping {} bytes from session {} since no endpoint peer sent to its slot",
+                datagram.payload.len(),
+                session_id
+            );
+            return;
+        }
+        debug!(
+            "Sending {} bytes from session {} {:?} to endpoint {:?}",
+            datagram.payload.len(),
+            session_id,
+            datagram.metadata,
+            peer_addrs
+        );
+        let packet = endpoint_packet(&self.args, datagram, local_addr);
+        peer_addrs.iter().for_each(|peer_addr| {
+            self.capture(
+                Direction::Inbound,
+                slot_index,
+                session_id,
+                datagram.metadata.source,
+                *peer_addr,
+                &datagram.payload,
+            )
+        });
+        if socket.peer_addr().is_ok() {
+            socket.send(&packet).unwrap_or_else(|e| {
+                if let Some(suppressed) = self.send_errors.admit() {
+                    error!(suppressed, "Can't send packets to endpoint: {}", e);
+                }
+                0
+            });
+        } else {
+            peer_addrs.iter().for_each(|peer_addr| {
+                socket.send_to(&packet, peer_addr).unwrap_or_else(|e| {
+                    if let Some(suppressed) = self.send_errors.admit() {
+                        error!(
+                            suppressed,
+                            "Can't send packets to endpoint {}: {}", peer_addr, e
+                        );
+                    }
+                    0
+                });
+            });
+        }
+    }
+
+    fn publish_to_slot(
+        &self,
+        index: usize,
+        slot: &ClientSlot,
+        datagram: Datagram,
+        local_addr: SocketAddr,
+    ) {
+        let _span = slot.span().enter();
+        let shaped = slot
+            .down()
+            .shape(&mut self.global_down.borrow_mut(), datagram);
+        if let Some(datagram) = shaped {
+            self.account(slot, Direction::Outbound, &datagram);
+            self.capture(
+                Direction::Outbound,
+                Some(index),
+                slot.session_id(),
+                datagram.metadata.source,
+                local_addr,
+                &datagram.payload,
+            );
+            slot.publish(&datagram);
+            self.published(index, &datagram);
+        }
+    }
+
+    /// Ties the learned endpoint peer a datagram comes from to the slot it was published to
+    fn published(&self, index: usize, datagram: &Datagram) {
+        if datagram.metadata.origin == Origin::Server {
+            self.peers
+                .borrow_mut()
+                .published(datagram.metadata.source, index);
+        }
+    }
+
+    /// Sends the traffic delayed by the rate limits, as far as tokens are available
+    fn release_deferred(&self, socket: &UdpSocket) {
+        let local_addr = socket
+            .local_addr()
+            .unwrap_or(SocketAddr::from_str("0.0.0.0:0").unwrap());
+        let slots = self.slots.borrow();
+        slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|slot| (index, slot)))
+            .for_each(|(index, slot)| {
+                let _span = slot.span().enter();
+                loop {
+                    let released = slot.down().release(&mut self.global_down.borrow_mut());
+                    match released {
+                        Some(datagram) => {
+                            self.account(slot, Direction::Outbound, &datagram);
+                            self.capture(
+                                Direction::Outbound,
+                                Some(index),
+                                slot.session_id(),
+                                datagram.metadata.source,
+                                local_addr,
+                                &datagram.payload,
+                            );
+                            slot.publish(&datagram);
+                            self.published(index, &datagram);
+                        }
+                        None => break,
+                    }
+                }
+                loop {
+                    let released = slot.up().release(&mut self.global_up.borrow_mut());
+                    match released {
+                        Some(datagram) => self.forward_from_client(
+                            socket,
+                            Some(index),
+                            slot.subscriber_session_id(),
+                            datagram,
+                        ),
+                        None => break,
+                    }
+                }
+            });
+    }
+
+    fn account(&self, slot: &ClientSlot, direction: Direction, datagram: &Datagram) {
+        self.accounting
+            .borrow_mut()
+            .record(slot.identity(), direction, datagram.payload.len());
+    }
+
+    fn capture(
+        &self,
+        direction: Direction,
+        slot: Option<usize>,
+        session_id: i32,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) {
+        if let Some(capture) = &self.capture {
+            capture.record(direction, slot, session_id, source, destination, payload);
+        }
+    }
+
+    fn log_statistics(&self) {
+        let mut accounting = self.accounting.borrow_mut();
+        let (global_up, global_down) = (self.global_up.borrow(), self.global_down.borrow());
+        if !global_up.is_unlimited() || !global_down.is_unlimited() {
+            info!("Global rate limits: up {}, down {}", global_up, global_down);
+        }
+        self.slots
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|slot| (index, slot)))
+            .for_each(|(index, slot)| {
+                let mut statistics = vec![];
+                if accounting.is_enabled() {
+                    statistics.push(format!(
+                        "{} traffic today: {}",
+                        slot.identity(),
+                        accounting.today(slot.identity())
+                    ));
+                }
+                if !slot.up().limiter.is_unlimited() || !slot.down().limiter.is_unlimited() {
+                    statistics.push(format!(
+                        "rate limits: up {}, down {}",
+                        slot.up().limiter,
+                        slot.down().limiter
+                    ));
+                }
+                if let Some(mut pinger) = slot.pinger().filter(|pinger| !pinger.stats.is_empty()) {
+                    statistics.push(format!("round trip time: {}", pinger.stats));
+                    pinger.stats.reset();
+                }
+                if let Some(multipath) = slot
+                    .multipath()
+                    .filter(|multipath| multipath.duplicates() > 0)
+                {
+                    statistics.push(format!(
+                        "dropped {} duplicate datagrams",
+                        multipath.duplicates()
+                    ));
+                }
+                if slot.deadline().stale() > 0 {
+                    statistics.push(format!(
+                        "dropped {} stale datagrams",
+                        slot.deadline().stale()
+                    ));
+                }
+                if slot.fec().has_losses() {
+                    statistics.push(format!("error correction: {}", slot.fec()));
+                }
+                if slot.compressor().compression() != Compression::None {
+                    statistics.push(format!(
+                        "{:?} compression: {}",
+                        slot.compressor().compression(),
+                        slot.compressor().stats()
+                    ));
+                }
+                if !statistics.is_empty() {
+                    let _span = slot.span().enter();
                     info!(
-                        "ClientSlot at position {}/{} is now on an active session_id={}",
-                        position + 1,
-                        self.args.max_clients,
-                        session_id
+                        "ClientSlot at position {}/{} {}",
+                        index + 1,
+                        self.max_clients(),
+                        statistics.join("; ")
                     );
                 }
             });
+        if accounting.is_enabled() {
+            accounting.save();
         }
     }
 
-    fn handle_disconnections(&self) {
-        if let Ok(mut guard) = DISCONNECTED_SESSIONS.try_lock() {
-            guard.drain(..).for_each(|session_id| {
-                let mut slots = self.slots.borrow_mut();
-                if let Some(position) = slots.iter()
-                    .position(|slot| {
-                        if let Some(slot) = slot {
-                            !slot.is_closed() &&
-                            (slot.is_publishing_on_session(session_id) || slot.has_subscribers_on_session(session_id))
-                        } else {
-                            false
+    fn handle_image_events(&self) {
+        let events: Vec<(usize, ImageEvent)> = self
+            .slots
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(position, slot)| slot.as_ref().map(|slot| (position, slot)))
+            .flat_map(|(position, slot)| {
+                slot.image_events()
+                    .into_iter()
+                    .map(move |event| (position, event))
+            })
+            .collect();
+        events
+            .into_iter()
+            .for_each(|(position, event)| match event {
+                ImageEvent::Available {
+                    session_id,
+                    source_identity,
+                    ..
+                } => {
+                    let slot = self.slots.borrow()[position].clone();
+                    if let Some(slot) = slot {
+                        slot.activate(session_id, &source_identity);
+                        let _span = slot.span().enter();
+                        info!(
+                            "ClientSlot at position {}/{} is now on an active session_id={} from {}",
+                            position + 1,
+                            self.max_clients(),
+                            session_id,
+                            source_identity
+                        );
+                        if !self.access.borrow().allows(&source_identity) {
+                            self.free_slot(position, "source is not allowed");
                         }
-                    }) {
-                    let slot = slots[position].take();
-                    slot.unwrap().close();
-                    info!("ClientSlot at position {}/{} is now free since associated session is closed", position + 1, self.args.max_clients)
+                    }
+                }
+                ImageEvent::Unavailable { session_id } => {
+                    let closed = self.slots.borrow()[position].as_ref().is_some_and(|slot| {
+                        !slot.is_closed()
+                            && slot.has_subscribers_on_session(session_id)
+                            && slot.deactivate(session_id)
+                    });
+                    if closed {
+                        self.free_slot(position, "associated session is closed");
+                    }
                 }
             });
-            if let Some(position) = self
-                .slots
-                .borrow()
-                .iter()
-                .position(|slot| slot.is_some() && slot.as_ref().unwrap().is_timeout_elapsed())
-            {
-                let slot = self.slots.borrow_mut()[position].take();
-                slot.unwrap().close();
-                info!(
-                    "ClientSlot at position {}/{} is now free since associated session timed out",
-                    position + 1,
-                    self.args.max_clients
-                );
-            }
+    }
+
+    fn handle_timeouts(&self) {
+        self.peers.borrow_mut().expire();
+        let position = self
+            .slots
+            .borrow()
+            .iter()
+            .position(|slot| slot.is_some() && slot.as_ref().unwrap().is_timeout_elapsed());
+        if let Some(position) = position {
+            self.free_slot(position, "associated session timed out");
+        }
+    }
+
+    /// Frees or throttles the slots of the clients which exceeded their quotas, and restores the
+    /// rate limits of the throttled ones when a new period begins
+    fn handle_quotas(&self) {
+        let exceeded: Vec<(usize, bool)> = self
+            .slots
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(position, slot)| slot.as_ref().map(|slot| (position, slot)))
+            .map(|(position, slot)| {
+                let exceeded = self.accounting.borrow().is_exceeded(slot.identity());
+                (position, exceeded)
+            })
+            .collect();
+        exceeded
+            .into_iter()
+            .for_each(|(position, exceeded)| match self.args.quota_action {
+                QuotaAction::Reject if exceeded => self.free_slot(position, "quota is exceeded"),
+                QuotaAction::Reject => {}
+                QuotaAction::Throttle => {
+                    let slots = self.slots.borrow();
+                    let slot = slots[position].as_ref().unwrap();
+                    if slot.is_throttled() != exceeded {
+                        let (up, down) = if exceeded {
+                            (&self.args.quota_throttle, &self.args.quota_throttle)
+                        } else {
+                            (&self.args.limit_up, &self.args.limit_down)
+                        };
+                        slot.up().limiter.set_limit(up);
+                        slot.down().limiter.set_limit(down);
+                        slot.set_throttled(exceeded);
+                        let _span = slot.span().enter();
+                        info!(
+                            "ClientSlot at position {}/{} of {} is {} throttled",
+                            position + 1,
+                            self.max_clients(),
+                            slot.identity(),
+                            if exceeded { "now" } else { "no longer" }
+                        );
+                    }
+                }
+            });
+    }
+
+    /// Pings, heartbeats and pending parity shards are sent to the clients connected to their
+    /// slots only
+    fn send_control_frames(&self) {
+        self.slots
+            .borrow()
+            .iter()
+            .flatten()
+            .filter(|slot| slot.source_identity().is_some())
+            .for_each(|slot| {
+                slot.ping();
+                slot.flush();
+                slot.heartbeat();
+            });
+    }
+
+    /// Keeps the source identities of the images on the handshake subscription, which
+    /// handshakes are accepted from or not, and on a single port server opens the response
+    /// channels to their publishers
+    fn track_handshake_sources(&self, subscription: &dyn Subscription) {
+        let mut sources = self.handshake_sources.borrow_mut();
+        let mut responses = self.responses.borrow_mut();
+        subscription
+            .image_events()
+            .into_iter()
+            .for_each(|event| match event {
+                ImageEvent::Available {
+                    session_id,
+                    source_identity,
+                    correlation_id,
+                } => {
+                    if self.args.single_port {
+                        let channel = format!(
+                            "aeron:udp?control-mode=response|control={}:{}{}|response-correlation-id={}",
+                            self.args.public, self.args.control, self.args.interface, correlation_id
+                        );
+                        responses.insert(session_id, Rc::from(self.transport.publication(&channel)));
+                    }
+                    sources.insert(session_id, source_identity);
+                }
+                ImageEvent::Unavailable { session_id } => {
+                    sources.remove(&session_id);
+                    if let Some(response) = responses.remove(&session_id) {
+                        response.close();
+                    }
+                }
+            });
+    }
+
+    /// Frees the slots of the clients no longer allowed once the access lists are reloaded
+    fn handle_access(&self) {
+        if !self.access.borrow_mut().reload() {
+            return;
         }
+        let denied: Vec<usize> = self
+            .slots
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(position, slot)| slot.as_ref().map(|slot| (position, slot)))
+            .filter(|(_, slot)| {
+                slot.source_identity()
+                    .is_some_and(|source| !self.access.borrow().allows(&source))
+            })
+            .map(|(position, _)| position)
+            .collect();
+        denied
+            .into_iter()
+            .for_each(|position| self.free_slot(position, "source is no longer allowed"));
+    }
+
+    fn free_slot(&self, position: usize, reason: &str) {
+        let mut slots = self.slots.borrow_mut();
+        let slot = slots[position].take().unwrap();
+        slot.close();
+        while slots.last().is_some_and(|slot| slot.is_none()) {
+            slots.pop();
+        }
+        drop(slots);
+        self.slot_manager.borrow_mut().release(position);
+        self.peers.borrow_mut().release_slot(position);
+        let _span = slot.span().enter();
+        info!(
+            "ClientSlot at position {}/{} is now free since {}",
+            position + 1,
+            self.max_clients(),
+            reason
+        );
+    }
+
+    /// Opens the subscription of a new slot on the first port of the range not taken by another
+    /// slot, skipping the ones that fail to bind
+    fn open_slot_port(&self, reliability: Reliability) -> Option<(usize, Box<dyn Subscription>)> {
+        let taken: Vec<usize> = self
+            .slots
+            .borrow()
+            .iter()
+            .flatten()
+            .map(|slot| slot.port())
+            .collect();
+        self.args
+            .port_range
+            .clone()
+            .filter(|port| !taken.contains(port))
+            .find_map(|port| {
+                let channel_forward = if self.args.ipc {
+                    ipc_channel(port)
+                } else {
+                    format!(
+                        "aeron:udp?endpoint=0.0.0.0:{}{}{}",
+                        port,
+                        self.args.interface,
+                        reliability.channel_parameter()
+                    )
+                };
+                match self.transport.try_subscription(&channel_forward) {
+                    Ok(subscription) => Some((port, subscription)),
+                    Err(e) => {
+                        warn!("Skipping port {} of the range: {}", port, e);
+                        None
+                    }
+                }
+            })
+    }
+
+    /// Opens the publication of a new slot on the first control port of the range not taken by
+    /// another slot, skipping the ones that fail to bind
+    fn open_slot_control(&self) -> Option<(usize, Box<dyn Publication>)> {
+        let taken: Vec<usize> = self
+            .slots
+            .borrow()
+            .iter()
+            .flatten()
+            .map(|slot| slot.control())
+            .collect();
+        self.args
+            .control_range
+            .clone()
+            .filter(|control| !taken.contains(control))
+            .find_map(|control| {
+                let channel_backward = if self.args.ipc {
+                    ipc_channel(control)
+                } else {
+                    format!(
+                        "aeron:udp?control={}:{}{}|control-mode=dynamic",
+                        self.args.public, control, self.args.interface
+                    )
+                };
+                match self.transport.try_publication(&channel_backward) {
+                    Ok(publication) => Some((control, publication)),
+                    Err(e) => {
+                        warn!("Skipping control port {} of the range: {}", control, e);
+                        None
+                    }
+                }
+            })
+    }
+
+    /// Name of the client the traffic is accounted to, as told by the source address of its
+    /// handshake, identities ending up in the tab separated accounting file
+    fn client_identity(&self, session_id: i32, request: &HandshakeRequest) -> String {
+        let sources = self.handshake_sources.borrow();
+        let source = sources.get(&session_id).map(String::as_str).unwrap_or("");
+        self.identities.identify(source, &request.identity)
     }
 
     fn handshake(
         &self,
-        header: &Header,
+        header: &FragmentHeader,
         request: HandshakeRequest,
         failure_details: FailureDetails,
         index: usize,
+        reply: Rc<dyn Publication>,
     ) -> Result<HandshakeResponse, Failure> {
+        let source = self
+            .handshake_sources
+            .borrow()
+            .get(&header.session_id)
+            .cloned()
+            .unwrap_or_default();
+        if !self.access.borrow().allows(&source) {
+            info!(
+                "Rejecting handshake from {} since it is not allowed",
+                source
+            );
+            return Err(Failure::HandshakeFailedNotAllowed(failure_details));
+        }
+
+        let identity = self.client_identity(header.session_id, &request);
+        if self.args.quota_action == QuotaAction::Reject
+            && self.accounting.borrow().is_exceeded(&identity)
+        {
+            info!(
+                "Rejecting handshake of {} since quota is exceeded",
+                identity
+            );
+            return Err(Failure::HandshakeFailedQuotaExceeded(failure_details));
+        }
+
         let slot_index = index + 1;
-        let port = self.args.port + slot_index;
-        let control = self.args.control + slot_index;
 
-        let channel_forward = format!("aeron:udp?endpoint=0.0.0.0:{}{}", port, self.args.interface);
-        let channel_backward = format!(
-            "aeron:udp?control={}:{}{}|control-mode=dynamic",
-            self.args.public, control, self.args.interface
-        );
+        let mut capabilities = request.capabilities.intersection(Capabilities::SUPPORTED);
+        // Best effort delivery is of no use over shared memory, which loses nothing
+        if self.args.reliability == Reliability::Reliable || self.args.single_port || self.args.ipc
+        {
+            capabilities = capabilities.without(Capabilities::BEST_EFFORT);
+        }
+        // Clients of a single port server have a single path, their handshake one, and so do the
+        // ones of an IPC server
+        if self.args.single_port || self.args.ipc {
+            capabilities = capabilities.without(Capabilities::MULTIPATH);
+        }
+        let reliability = Reliability::negotiated(capabilities);
+        let (port, control, publication, subscription) = if self.args.single_port {
+            let channel_forward = format!(
+                "aeron:udp?endpoint=0.0.0.0:{}{}|session-id={}",
+                self.args.port, self.args.interface, header.session_id
+            );
+            (
+                self.args.port,
+                self.args.control,
+                reply,
+                self.transport.subscription(&channel_forward),
+            )
+        } else {
+            let Some((port, subscription)) = self.open_slot_port(reliability) else {
+                info!("Rejecting handshake since no port of the range is free");
+                return Err(Failure::HandshakeFailedServerFull(failure_details));
+            };
+            let Some((control, publication)) = self.open_slot_control() else {
+                subscription.close();
+                info!("Rejecting handshake since no control port of the range is free");
+                return Err(Failure::HandshakeFailedServerFull(failure_details));
+            };
+            (port, control, Rc::from(publication), subscription)
+        };
+        if self.args.heartbeat_interval == 0 {
+            capabilities = capabilities.without(Capabilities::HEARTBEAT);
+        }
+        let heartbeat_interval = capabilities
+            .contains(Capabilities::HEARTBEAT)
+            .then(|| Duration::from_millis(self.args.heartbeat_interval));
+        // Clients sending heartbeats are dead once they miss enough of them
+        if self.args.ping_interval == 0 {
+            capabilities = capabilities.without(Capabilities::PING);
+        }
+        let ping_interval = capabilities
+            .contains(Capabilities::PING)
+            .then(|| Duration::from_millis(self.args.ping_interval));
+        let session_timeout = heartbeat_interval
+            .map(|interval| interval * self.args.heartbeat_misses)
+            .unwrap_or(self.settings.session_timeout);
+        let compression = request
+            .compression
+            .iter()
+            .filter(|_| capabilities.contains(Capabilities::COMPRESSION))
+            .find(|compression| self.args.compression.contains(compression))
+            .copied()
+            .unwrap_or(Compression::None);
         let client_slot = ClientSlot::new(
-            &self.settings,
-            channel_forward,
-            channel_backward,
+            publication,
+            subscription,
+            identity.clone(),
+            index,
             port,
             control,
+            self.settings.connection_timeout,
+            session_timeout,
+            heartbeat_interval,
+            ping_interval,
+            Compressor::new(compression, self.args.compression_threshold),
+            Deadline::new(self.args.max_age, capabilities),
+            capabilities.contains(Capabilities::MULTIPATH),
+            Fec::new(self.args.fec, capabilities),
+            Shaper::new(&self.args.limit_up, self.args.limit_action),
+            Shaper::new(&self.args.limit_down, self.args.limit_action),
         );
 
-        let encrypted_session_id = header.session_id().wrapping_mul(request.key);
+        let encrypted_session_id = header.session_id.wrapping_mul(request.key);
         debug!(
             "Computing verification: {} * {} = {}",
-            header.session_id(),
-            request.key,
-            encrypted_session_id
+            header.session_id, request.key, encrypted_session_id
         );
 
         let handshake_response = HandshakeResponse {
             verification: encrypted_session_id,
-            port,
-            control,
+            port: port + self.args.port_offset,
+            control: control + self.args.port_offset,
+            capabilities,
+            compression,
+            heartbeat_interval: heartbeat_interval
+                .map(|interval| interval.as_millis() as u64)
+                .unwrap_or(0),
         };
+        let _span = client_slot.span().clone().entered();
         info!(
             "Client handshake on slot {}/{}, sending {:?}",
-            slot_index, self.args.max_clients, handshake_response
+            slot_index,
+            self.max_clients(),
+            handshake_response
         );
 
-        if self.slots.borrow_mut()[index]
-            .replace(client_slot)
-            .is_none()
-        {
+        // The client is already connected, on its handshake session
+        if self.args.single_port {
+            client_slot.activate(header.session_id, &source);
+            self.responses.borrow_mut().remove(&header.session_id);
+        }
+        let mut slots = self.slots.borrow_mut();
+        if index >= slots.len() {
+            slots.resize(index + 1, None);
+        }
+        if slots[index].replace(client_slot).is_none() {
+            self.slot_manager.borrow_mut().assign(index, &identity);
             Ok(handshake_response)
         } else {
             Err(Failure::HandshakeFailedTooManyConnections(failure_details))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::messages::MAGIC;
+    use crate::test_arguments;
+    use crate::transport::memory::MemoryBus;
+    use std::net::UdpSocket;
+    use std::thread::JoinHandle;
+
+    fn settings(args: &Arguments) -> Settings {
+        let mut settings = Settings::new(args);
+        settings.handshake_retry = Duration::from_millis(50);
+        settings.connection_timeout = Duration::from_millis(300);
+        settings
+    }
+
+    fn free_endpoint() -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().to_string()
+    }
+
+    fn spawn_server(bus: &MemoryBus, args: Arguments, running: Arc<AtomicBool>) -> JoinHandle<()> {
+        let bus = bus.clone();
+        std::thread::spawn(move || {
+            let transport = bus.transport("127.0.0.1:40123");
+            Server::new(&args, settings(&args), Box::new(transport)).start(running)
+        })
+    }
+
+    fn handshake(bus: &MemoryBus, identity: &str) -> Result<HandshakeResponse, Failure> {
+        let args = test_arguments(&free_endpoint(), false);
+        let client = Client::new(&args, settings(&args), Box::new(bus.transport(identity)));
+        client.handshake(&Arc::new(AtomicBool::new(true))).unwrap()
+    }
+
+    /// Application and service sockets at both ends of a tunnel through a server and a client
+    /// on the bus, the application sending to the endpoint of the client
+    struct Tunnel {
+        application: UdpSocket,
+        service: UdpSocket,
+        endpoint: String,
+        running: Arc<AtomicBool>,
+        handles: Vec<JoinHandle<()>>,
+    }
+
+    impl Tunnel {
+        fn stop(self) {
+            self.running.store(false, Ordering::SeqCst);
+            self.handles
+                .into_iter()
+                .for_each(|handle| handle.join().unwrap());
+        }
+    }
+
+    /// Starts a server in front of a new service and a client with the given arguments in front
+    /// of a new application, its endpoint replacing the one of the arguments
+    fn tunnel(bus: &MemoryBus, client_args: Arguments) -> Tunnel {
+        let running = Arc::new(AtomicBool::new(true));
+        let service = UdpSocket::bind("127.0.0.1:0").unwrap();
+        service
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let server_args = test_arguments(&service.local_addr().unwrap().to_string(), true);
+        let server = spawn_server(bus, server_args, running.clone());
+
+        let endpoint = free_endpoint();
+        let client_args = Arguments {
+            endpoint: endpoint.clone(),
+            ..client_args
+        };
+        let client = {
+            let transport = bus.transport("127.0.0.1:50000");
+            let running = running.clone();
+            std::thread::spawn(move || {
+                Client::new(&client_args, settings(&client_args), Box::new(transport))
+                    .start(running)
+            })
+        };
+
+        let application = UdpSocket::bind("127.0.0.1:0").unwrap();
+        application
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        Tunnel {
+            application,
+            service,
+            endpoint,
+            running,
+            handles: vec![client, server],
+        }
+    }
+
+    /// Sends a request from the application to the service and a response back, returning the
+    /// address the service received the request from
+    fn round_trip(tunnel: &Tunnel) -> SocketAddr {
+        let mut buffer = [0; 1500];
+        // Datagrams are dropped until the client has completed the handshake
+        let (n, service_peer) = (0..50)
+            .find_map(|_| {
+                tunnel
+                    .application
+                    .send_to(b"request", &tunnel.endpoint)
+                    .unwrap();
+                tunnel.service.recv_from(&mut buffer).ok()
+            })
+            .expect("Datagram not forwarded to the service");
+        assert_eq!(&buffer[..n], b"request");
+
+        tunnel.service.send_to(b"response", service_peer).unwrap();
+        let n = tunnel.application.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"response");
+        service_peer
+    }
+
+    #[test]
+    fn test_handshake_and_forwarding() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let service = UdpSocket::bind("127.0.0.1:0").unwrap();
+        service
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let server_args = Arguments {
+            compression: vec![Compression::Lz4],
+            compression_threshold: 0,
+            ..test_arguments(&service.local_addr().unwrap().to_string(), true)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        let endpoint = free_endpoint();
+        let client_args = Arguments {
+            compression: vec![Compression::Lz4],
+            ..test_arguments(&endpoint, false)
+        };
+        let client = {
+            let bus = bus.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                let transport = bus.transport("127.0.0.1:50000");
+                Client::new(&client_args, settings(&client_args), Box::new(transport))
+                    .start(running)
+            })
+        };
+
+        let application = UdpSocket::bind("127.0.0.1:0").unwrap();
+        application
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buffer = [0; 1500];
+        // Datagrams are dropped until the client has completed the handshake
+        let (n, service_peer) = (0..50)
+            .find_map(|_| {
+                application.send_to(b"request", &endpoint).unwrap();
+                service.recv_from(&mut buffer).ok()
+            })
+            .expect("Datagram not forwarded to the service");
+        assert_eq!(&buffer[..n], b"request");
+
+        service.send_to(b"response", service_peer).unwrap();
+        let n = application.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"response");
+
+        running.store(false, Ordering::SeqCst);
+        client.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_single_port() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let service = UdpSocket::bind("127.0.0.1:0").unwrap();
+        service
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let server_args = Arguments {
+            single_port: true,
+            ..test_arguments(&service.local_addr().unwrap().to_string(), true)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        let endpoint = free_endpoint();
+        let client_args = Arguments {
+            single_port: true,
+            ..test_arguments(&endpoint, false)
+        };
+        let client = {
+            let bus = bus.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                let transport = bus.transport("127.0.0.1:50000");
+                Client::new(&client_args, settings(&client_args), Box::new(transport))
+                    .start(running)
+            })
+        };
+
+        let application = UdpSocket::bind("127.0.0.1:0").unwrap();
+        application
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buffer = [0; 1500];
+        let (n, service_peer) = (0..50)
+            .find_map(|_| {
+                application.send_to(b"request", &endpoint).unwrap();
+                service.recv_from(&mut buffer).ok()
+            })
+            .expect("Datagram not forwarded to the service");
+        assert_eq!(&buffer[..n], b"request");
+
+        service.send_to(b"response", service_peer).unwrap();
+        let n = application.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"response");
+
+        // Other clients are served through the same ports
+        let args = Arguments {
+            single_port: true,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let other = Client::new(
+            &args,
+            settings(&args),
+            Box::new(bus.transport("127.0.0.1:50001")),
+        );
+        let response = other
+            .handshake(&Arc::new(AtomicBool::new(true)))
+            .unwrap()
+            .unwrap();
+        assert_eq!((response.port, response.control), (40123, 32104));
+
+        running.store(false, Ordering::SeqCst);
+        client.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_ipc() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let service = UdpSocket::bind("127.0.0.1:0").unwrap();
+        service
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let server_args = Arguments {
+            ipc: true,
+            ..test_arguments(&service.local_addr().unwrap().to_string(), true)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        let endpoint = free_endpoint();
+        let client_args = Arguments {
+            ipc: true,
+            ..test_arguments(&endpoint, false)
+        };
+        let client = {
+            let bus = bus.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                let transport = bus.transport("aeron:ipc");
+                Client::new(&client_args, settings(&client_args), Box::new(transport))
+                    .start(running)
+            })
+        };
+
+        let application = UdpSocket::bind("127.0.0.1:0").unwrap();
+        application
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buffer = [0; 1500];
+        let (n, service_peer) = (0..50)
+            .find_map(|_| {
+                application.send_to(b"request", &endpoint).unwrap();
+                service.recv_from(&mut buffer).ok()
+            })
+            .expect("Datagram not forwarded to the service");
+        assert_eq!(&buffer[..n], b"request");
+
+        service.send_to(b"response", service_peer).unwrap();
+        let n = application.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"response");
+
+        // Other clients share the handshake streams, and are told the streams of their slot
+        let args = Arguments {
+            ipc: true,
+            reliability: Reliability::BestEffort,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let other = Client::new(&args, settings(&args), Box::new(bus.transport("aeron:ipc")));
+        let response = other
+            .handshake(&Arc::new(AtomicBool::new(true)))
+            .unwrap()
+            .unwrap();
+        assert_eq!((response.port, response.control), (40125, 32106));
+        assert_eq!(
+            Reliability::negotiated(response.capabilities),
+            Reliability::Reliable
+        );
+
+        running.store(false, Ordering::SeqCst);
+        client.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_multipath_deduplication() {
+        let bus = MemoryBus::new();
+        let client_args = Arguments {
+            interfaces: vec![
+                String::from("|interface=10.0.0.2"),
+                String::from("|interface=192.168.1.2"),
+            ],
+            ..test_arguments("", false)
+        };
+        let tunnel = tunnel(&bus, client_args);
+        let Tunnel {
+            application,
+            service,
+            endpoint,
+            ..
+        } = &tunnel;
+
+        let service_peer = round_trip(&tunnel);
+        let mut buffer = [0; 1500];
+        // Sent on both paths, delivered once
+        application.send_to(b"again", endpoint).unwrap();
+        let n = (0..10)
+            .find_map(|_| {
+                service
+                    .recv(&mut buffer)
+                    .ok()
+                    .filter(|n| &buffer[..*n] != b"request")
+            })
+            .unwrap();
+        assert_eq!(&buffer[..n], b"again");
+        assert!(service.recv(&mut buffer).is_err());
+
+        // Received on both paths, delivered once
+        service.send_to(b"again", service_peer).unwrap();
+        let n = (0..10)
+            .find_map(|_| application.recv(&mut buffer).ok())
+            .unwrap();
+        assert_eq!(&buffer[..n], b"again");
+        assert!(application.recv(&mut buffer).is_err());
+
+        tunnel.stop();
+    }
+
+    #[test]
+    fn test_server_full() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            max_clients: 1,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        let response = handshake(&bus, "127.0.0.1:50000").unwrap();
+        assert_eq!(response.port, 40124);
+        assert_eq!(response.control, 32105);
+        assert!(matches!(
+            handshake(&bus, "127.0.0.1:50001"),
+            Err(Failure::HandshakeFailedServerFull(_))
+        ));
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_port_range() {
+        let bus = MemoryBus::new();
+        bus.take_port(40124);
+        bus.take_port(32105);
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            port_range: 40124..=40126,
+            control_range: 32105..=32107,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        // Ports already bound are skipped, and the pool is bounded by the range
+        let response = handshake(&bus, "127.0.0.1:50000").unwrap();
+        assert_eq!((response.port, response.control), (40125, 32106));
+        let response = handshake(&bus, "127.0.0.1:50001").unwrap();
+        assert_eq!((response.port, response.control), (40126, 32107));
+        assert!(matches!(
+            handshake(&bus, "127.0.0.1:50002"),
+            Err(Failure::HandshakeFailedServerFull(_))
+        ));
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_redirect() {
+        let path = std::env::temp_dir().join(format!("drain-{}", std::process::id()));
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            max_clients: 2,
+            redirect: vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()],
+            drain_file: Some(path.to_str().unwrap().to_owned()),
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        assert!(handshake(&bus, "127.0.0.1:50000").is_ok());
+        std::fs::write(&path, "").unwrap();
+        let redirected = |identity| match handshake(&bus, identity) {
+            Err(Failure::HandshakeRedirect(_, server)) => Some(server.to_string()),
+            _ => None,
+        };
+        assert_eq!(redirected("127.0.0.1:50001").unwrap(), "192.0.2.1");
+        std::fs::remove_file(&path).unwrap();
+        assert!(handshake(&bus, "127.0.0.1:50002").is_ok());
+        // Full, in turn
+        assert_eq!(redirected("127.0.0.1:50003").unwrap(), "192.0.2.2");
+        assert_eq!(redirected("127.0.0.1:50004").unwrap(), "192.0.2.1");
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_slot_connection_timeout() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            max_clients: 1,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        assert!(handshake(&bus, "127.0.0.1:50000").is_ok());
+        // The first client never connects to its slot, which is freed after the timeout
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(handshake(&bus, "127.0.0.1:50001").is_ok());
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_quota_exceeded() {
+        let path = std::env::temp_dir().join(format!("quota-{}.tsv", std::process::id()));
+        let identities = std::env::temp_dir().join(format!("identities-{}", std::process::id()));
+        let today = chrono::Utc::now().format("%Y-%m-%d");
+        std::fs::write(&path, format!("team\t{}\t5\t500\t0\t0\n", today)).unwrap();
+        std::fs::write(&identities, "127.0.0.1 team\n").unwrap();
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            identities: Some(identities.to_str().unwrap().to_owned()),
+            accounting: Some(path.to_str().unwrap().to_owned()),
+            daily_quota: "5:".parse().unwrap(),
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        assert!(matches!(
+            handshake(&bus, "127.0.0.1:50000"),
+            Err(Failure::HandshakeFailedQuotaExceeded(_))
+        ));
+        // The quota goes with the source address, not with the name the client declares
+        assert!(handshake(&bus, "127.0.0.2:50000").is_ok());
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&identities).unwrap();
+    }
+
+    #[test]
+    fn test_handshake_not_allowed() {
+        let path = std::env::temp_dir().join(format!("deny-{}", std::process::id()));
+        std::fs::write(&path, "127.0.0.2/32\n").unwrap();
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            deny: Some(path.to_str().unwrap().to_owned()),
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        assert!(matches!(
+            handshake(&bus, "127.0.0.2:50000"),
+            Err(Failure::HandshakeFailedNotAllowed(_))
+        ));
+        assert!(handshake(&bus, "127.0.0.1:50001").is_ok());
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reliability_negotiation() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            reliability: Reliability::BestEffort,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        let response = handshake(&bus, "127.0.0.1:50000").unwrap();
+        assert_eq!(
+            Reliability::negotiated(response.capabilities),
+            Reliability::Reliable
+        );
+        let client_args = Arguments {
+            reliability: Reliability::BestEffort,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let client = Client::new(
+            &client_args,
+            settings(&client_args),
+            Box::new(bus.transport("127.0.0.1:50001")),
+        );
+        let response = client
+            .handshake(&Arc::new(AtomicBool::new(true)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Reliability::negotiated(response.capabilities),
+            Reliability::BestEffort
+        );
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_incompatible_version() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let args = test_arguments(&free_endpoint(), false);
+        let server = spawn_server(&bus, args.clone(), running.clone());
+
+        let transport = bus.transport("127.0.0.1:50000");
+        let subscription = transport.subscription(&format!("aeron:udp?{}", args.cbackward));
+        let publication = transport.publication(&format!("aeron:udp?{}", args.cforward));
+        let request = bincode::serialize(&(MAGIC, PROTOCOL_VERSION + 1, 7, "future")).unwrap();
+        let mut reply = None;
+        for _ in 0..100 {
+            if !subscription.image_events().is_empty() {
+                publication.publish(&request);
+            }
+            subscription.poll(&mut |buffer, _| reply = HandshakeReply::decode(buffer));
+            if reply.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        match reply {
+            Some(Err(Failure::HandshakeFailedIncompatibleVersion(details, version))) => {
+                assert_eq!(details.session_id, publication.session_id().wrapping_mul(7));
+                assert_eq!(version, PROTOCOL_VERSION);
+            }
+            other => panic!("Unexpected handshake reply {:?}", other),
+        }
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_dead_client_detection() {
+        let bus = MemoryBus::new();
+        let running = Arc::new(AtomicBool::new(true));
+        let server_args = Arguments {
+            max_clients: 1,
+            ..test_arguments(&free_endpoint(), false)
+        };
+        let server = spawn_server(&bus, server_args, running.clone());
+
+        let response = handshake(&bus, "127.0.0.1:50000").unwrap();
+        assert_eq!(response.heartbeat_interval, 50);
+        // The client connects to its slot, then goes silent without closing its publication
+        let transport = bus.transport("127.0.0.1:50000");
+        let publication =
+            transport.publication(&format!("aeron:udp?endpoint=127.0.0.1:{}", response.port));
+        publication.publish(&bincode::serialize(&Frame::Heartbeat).unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(matches!(
+            handshake(&bus, "127.0.0.1:50001"),
+            Err(Failure::HandshakeFailedServerFull(_))
+        ));
+        // Freed after missing 3 heartbeats of 50ms, well before the session timeout
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(handshake(&bus, "127.0.0.1:50002").is_ok());
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// How the slot of a new client is picked among the free ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotPolicy {
+    /// The free slot with the lowest position
+    FirstFree,
+    /// The free slot following the last one assigned, wrapping around
+    RoundRobin,
+    /// The free slot released the longest ago, the never used ones first
+    LeastRecentlyUsed,
+    /// The slot the client was first assigned, which no other client is given
+    Reserved,
+}
+
+impl FromStr for SlotPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-free" => Ok(SlotPolicy::FirstFree),
+            "round-robin" => Ok(SlotPolicy::RoundRobin),
+            "least-recently-used" => Ok(SlotPolicy::LeastRecentlyUsed),
+            "reserved" => Ok(SlotPolicy::Reserved),
+            _ => Err(format!("Unknown slot policy {}", s)),
+        }
+    }
+}
+
+/// Picks the positions of the slots of new clients according to a policy. A position is only
+/// committed to once the handshake of the client succeeded, so that rejected clients leave no
+/// trace in the policy.
+pub struct SlotManager {
+    policy: SlotPolicy,
+    last_assigned: Option<usize>,
+    /// Order in which the slots were released, by position
+    releases: HashMap<usize, u64>,
+    released: u64,
+    reservations: HashMap<String, usize>,
+}
+
+impl SlotManager {
+    pub fn new(policy: SlotPolicy) -> Self {
+        SlotManager {
+            policy,
+            last_assigned: None,
+            releases: HashMap::new(),
+            released: 0,
+            reservations: HashMap::new(),
+        }
+    }
+
+    /// Position of the slot to give a new client, given which positions are occupied, none
+    /// when there are already as many clients as allowed. Positions past the occupied ones are
+    /// free, up to the maximum number of clients.
+    pub fn allocate(&self, occupied: &[bool], max_clients: usize, identity: &str) -> Option<usize> {
+        if occupied.iter().filter(|occupied| **occupied).count() >= max_clients {
+            return None;
+        }
+        let positions = occupied.len().max(max_clients);
+        let is_free = |position: &usize| !occupied.get(*position).copied().unwrap_or(false);
+        let mut free = (0..positions).filter(is_free);
+        match self.policy {
+            SlotPolicy::FirstFree => free.next(),
+            SlotPolicy::RoundRobin => {
+                let start = self.last_assigned.map_or(0, |last| last + 1);
+                (0..positions)
+                    .map(|offset| (start + offset) % positions)
+                    .find(is_free)
+            }
+            SlotPolicy::LeastRecentlyUsed => {
+                free.min_by_key(|position| (self.releases.get(position).copied(), *position))
+            }
+            SlotPolicy::Reserved => match self.reservations.get(identity) {
+                Some(position) => Some(*position).filter(is_free),
+                None => free.find(|position| !self.reservations.values().any(|p| p == position)),
+            },
+        }
+    }
+
+    /// Commits to the position allocated to a client, once its handshake succeeded
+    pub fn assign(&mut self, position: usize, identity: &str) {
+        self.last_assigned = Some(position);
+        if self.policy == SlotPolicy::Reserved {
+            self.reservations.insert(identity.to_owned(), position);
+        }
+    }
+
+    /// Records that the slot at a position was freed
+    pub fn release(&mut self, position: usize) {
+        self.released += 1;
+        self.releases.insert(position, self.released);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_free() {
+        let mut manager = SlotManager::new(SlotPolicy::FirstFree);
+        assert_eq!(manager.allocate(&[], 3, "a"), Some(0));
+        assert_eq!(manager.allocate(&[true, false, true], 3, "a"), Some(1));
+        assert_eq!(manager.allocate(&[true, true], 3, "a"), Some(2));
+        assert_eq!(manager.allocate(&[true, true, true], 3, "a"), None);
+        // Clients beyond a lowered maximum keep their slots, new ones wait for them to leave
+        manager.release(0);
+        assert_eq!(manager.allocate(&[false, true, true], 1, "a"), None);
+        assert_eq!(manager.allocate(&[false, true, false], 2, "a"), Some(0));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut manager = SlotManager::new(SlotPolicy::RoundRobin);
+        assert_eq!(manager.allocate(&[], 3, "a"), Some(0));
+        manager.assign(0, "a");
+        assert_eq!(manager.allocate(&[false], 3, "b"), Some(1));
+        manager.assign(1, "b");
+        // Freed slots are only reused once the others were
+        assert_eq!(manager.allocate(&[false, false], 3, "c"), Some(2));
+        manager.assign(2, "c");
+        assert_eq!(manager.allocate(&[true, false, true], 3, "d"), Some(1));
+        // Allocations not committed to don't move on
+        assert_eq!(manager.allocate(&[false, false, true], 3, "d"), Some(0));
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let mut manager = SlotManager::new(SlotPolicy::LeastRecentlyUsed);
+        manager.assign(0, "a");
+        manager.assign(1, "b");
+        manager.release(1);
+        manager.release(0);
+        // Never used slots first, then the ones released the longest ago
+        assert_eq!(manager.allocate(&[false, false], 3, "c"), Some(2));
+        assert_eq!(manager.allocate(&[false, false, true], 3, "c"), Some(1));
+        manager.release(1);
+        assert_eq!(manager.allocate(&[false, false, true], 3, "c"), Some(0));
+    }
+
+    #[test]
+    fn test_reserved() {
+        let mut manager = SlotManager::new(SlotPolicy::Reserved);
+        assert_eq!(manager.allocate(&[], 3, "a"), Some(0));
+        manager.assign(0, "a");
+        assert_eq!(manager.allocate(&[true], 3, "b"), Some(1));
+        manager.assign(1, "b");
+        manager.release(0);
+        // Reserved slots are left to their clients, who get them back
+        assert_eq!(manager.allocate(&[false, true], 3, "c"), Some(2));
+        manager.assign(2, "c");
+        assert_eq!(manager.allocate(&[false, true, true], 3, "d"), None);
+        assert_eq!(manager.allocate(&[false, true, true], 3, "a"), Some(0));
+        // A client whose slot is still held waits for it
+        assert_eq!(manager.allocate(&[true, false, true], 3, "a"), None);
+    }
+}
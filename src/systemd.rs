@@ -0,0 +1,249 @@
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::fd::RawFd;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info};
+
+/// First file descriptor passed by socket activation
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Service manager notifications, as sent by `sd_notify`: readiness, status and watchdog
+/// keep-alives. Without a service manager listening, that is outside of a systemd unit of type
+/// `notify`, notifications go nowhere.
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<(std::os::unix::net::UnixDatagram, String)>,
+    watchdog: Option<Duration>,
+    last_watchdog: Cell<Instant>,
+    ready: Cell<bool>,
+    status: RefCell<String>,
+}
+
+impl Notifier {
+    /// Notifies the service manager given by the environment, if any
+    pub fn from_env() -> Self {
+        let pid_matches = |variable: &str| {
+            env::var(variable)
+                .ok()
+                .and_then(|pid| pid.parse::<u32>().ok())
+                .is_none_or(|pid| pid == std::process::id())
+        };
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| pid_matches("WATCHDOG_PID"))
+            .and_then(|usec| usec.parse().ok())
+            .filter(|usec| *usec > 0)
+            .map(Duration::from_micros);
+        Notifier::new(env::var("NOTIFY_SOCKET").ok(), watchdog)
+    }
+
+    fn new(path: Option<String>, watchdog: Option<Duration>) -> Self {
+        #[cfg(not(unix))]
+        let _ = path;
+        Notifier {
+            #[cfg(unix)]
+            socket: path.and_then(|path| {
+                std::os::unix::net::UnixDatagram::unbound()
+                    .map_err(|e| error!("Can't notify the service manager: {}", e))
+                    .ok()
+                    .map(|socket| (socket, path))
+            }),
+            watchdog,
+            last_watchdog: Cell::new(Instant::now()),
+            ready: Cell::new(false),
+            status: RefCell::new(String::new()),
+        }
+    }
+
+    /// Tells the service manager that startup is complete, the first time, and updates the
+    /// status line afterwards
+    pub fn ready(&self, status: &str) {
+        if self.ready.replace(true) {
+            return self.status(status);
+        }
+        *self.status.borrow_mut() = status.to_owned();
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+        if let Some(watchdog) = self.watchdog {
+            info!(
+                "Notifying the service manager watchdog every {:?}",
+                watchdog / 2
+            );
+        }
+    }
+
+    /// Updates the status line shown by the service manager, when it changed
+    pub fn status(&self, status: &str) {
+        if *self.status.borrow() != status {
+            *self.status.borrow_mut() = status.to_owned();
+            self.notify(&format!("STATUS={}", status));
+        }
+    }
+
+    /// Keeps the watchdog from firing, to be called from every loop, notifying it twice per
+    /// interval
+    pub fn watchdog(&self) {
+        if self
+            .watchdog
+            .is_some_and(|watchdog| self.last_watchdog.get().elapsed() >= watchdog / 2)
+        {
+            self.last_watchdog.set(Instant::now());
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    /// Tells the service manager that shutdown started
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    #[cfg(unix)]
+    fn notify(&self, state: &str) {
+        let Some((socket, path)) = &self.socket else {
+            return;
+        };
+        let sent = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                std::os::unix::net::SocketAddr::from_abstract_name(name)
+                    .and_then(|address| socket.send_to_addr(state.as_bytes(), &address))
+            }
+            _ => socket.send_to(state.as_bytes(), path),
+        };
+        if let Err(e) = sent {
+            error!("Can't notify the service manager on {}: {}", path, e);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn notify(&self, _state: &str) {}
+}
+
+/// The UDP socket passed by the service manager through socket activation, if any. Any other
+/// kind of socket is left alone, for the endpoint to be bound instead.
+#[cfg(unix)]
+pub fn activated_socket() -> Option<UdpSocket> {
+    use std::os::fd::FromRawFd;
+
+    let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let fds = env::var("LISTEN_FDS").ok()?.parse::<i32>().ok()?;
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        error!(
+            "Using the first of the {} sockets passed by the service manager",
+            fds
+        );
+    }
+    // Child processes are not to take the socket over
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !is_datagram_socket(LISTEN_FDS_START) {
+        error!("The socket passed by the service manager is not a datagram one, binding instead");
+        return None;
+    }
+    // Nor is the media driver, launched as a child process, to inherit it
+    set_close_on_exec(LISTEN_FDS_START);
+    // Safety: the service manager passes the descriptors open, starting from the third one
+    Some(unsafe { UdpSocket::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Whether the descriptor is a datagram socket, as `sd_is_socket` checks
+#[cfg(unix)]
+fn is_datagram_socket(fd: RawFd) -> bool {
+    let mut socket_type: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safety: the option value and its length point to a c_int
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    result == 0 && socket_type == libc::SOCK_DGRAM
+}
+
+#[cfg(unix)]
+fn set_close_on_exec(fd: RawFd) {
+    // Safety: fcntl only reads and sets the flags of the descriptor
+    let result = unsafe {
+        match libc::fcntl(fd, libc::F_GETFD) {
+            -1 => -1,
+            flags => libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC),
+        }
+    };
+    if result == -1 {
+        error!(
+            "Can't keep the activated socket from child processes: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn activated_socket() -> Option<UdpSocket> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_notifications() {
+        let path = env::temp_dir().join(format!("notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager.set_nonblocking(true).unwrap();
+        let notifier = Notifier::new(
+            Some(path.to_string_lossy().into_owned()),
+            Some(Duration::ZERO),
+        );
+        let mut buffer = [0; 256];
+        let mut received = || {
+            let n = manager.recv(&mut buffer).unwrap_or(0);
+            String::from_utf8_lossy(&buffer[..n]).into_owned()
+        };
+
+        notifier.ready("Waiting for handshakes");
+        assert_eq!(received(), "READY=1\nSTATUS=Waiting for handshakes");
+        // Unchanged statuses are not sent again, nor readiness
+        notifier.status("Waiting for handshakes");
+        notifier.ready("1 client connected");
+        assert_eq!(received(), "STATUS=1 client connected");
+        notifier.watchdog();
+        assert_eq!(received(), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(received(), "STOPPING=1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_activated_socket_checks() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(is_datagram_socket(socket.as_raw_fd()));
+        assert!(!is_datagram_socket(listener.as_raw_fd()));
+        assert!(!is_datagram_socket(-1));
+
+        // Safety: the flags of a descriptor owned by the test are only read
+        let flags = || unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_GETFD) };
+        unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFD, 0) };
+        assert_eq!(flags() & libc::FD_CLOEXEC, 0);
+        set_close_on_exec(socket.as_raw_fd());
+        assert_eq!(flags() & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::transport::{FragmentHeader, ImageEvent, Publication, Subscription, Transport};
+
+const STREAM_ID: i32 = 1001;
+
+struct PublicationEntry {
+    key: String,
+    session_id: i32,
+    source_identity: String,
+    /// Correlation id of the images of this publication
+    correlation_id: i64,
+    /// Key of the response channel answering this publication, if it asked for one
+    response_key: Option<String>,
+}
+
+struct SubscriptionState {
+    key: String,
+    registration_id: i64,
+    /// Session the subscription is restricted to, if any
+    session_id: Option<i32>,
+    images: HashMap<i32, String>,
+    fragments: VecDeque<(i32, Vec<u8>)>,
+    events: Vec<ImageEvent>,
+}
+
+impl SubscriptionState {
+    fn add_image(&mut self, publication: &PublicationEntry) {
+        if self
+            .session_id
+            .is_some_and(|session_id| session_id != publication.session_id)
+        {
+            return;
+        }
+        self.images
+            .insert(publication.session_id, publication.source_identity.clone());
+        self.events.push(ImageEvent::Available {
+            session_id: publication.session_id,
+            source_identity: publication.source_identity.clone(),
+            correlation_id: publication.correlation_id,
+        });
+    }
+
+    fn remove_image(&mut self, session_id: i32) {
+        if self.images.remove(&session_id).is_some() {
+            self.events.push(ImageEvent::Unavailable { session_id });
+        }
+    }
+}
+
+#[derive(Default)]
+struct BusState {
+    next_session_id: i32,
+    next_correlation_id: i64,
+    publications: Vec<PublicationEntry>,
+    subscriptions: Vec<Arc<Mutex<SubscriptionState>>>,
+    /// Ports bound by another program, which subscriptions fail to listen on and publications
+    /// fail to take as control port
+    taken_ports: Vec<usize>,
+}
+
+/// In-memory medium connecting the publications and subscriptions of several transports,
+/// matched by channel port, so that tunnels can run without the Aeron media driver.
+#[derive(Clone, Default)]
+pub struct MemoryBus {
+    state: Arc<Mutex<BusState>>,
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A transport whose publications are identified by subscribers with the given source identity
+    pub fn transport(&self, source_identity: &str) -> MemoryTransport {
+        MemoryTransport {
+            bus: self.clone(),
+            source_identity: source_identity.to_owned(),
+        }
+    }
+
+    /// Makes the channels binding a port fail, as if another program bound it
+    pub fn take_port(&self, port: usize) {
+        self.state.lock().unwrap().taken_ports.push(port);
+    }
+}
+
+pub struct MemoryTransport {
+    bus: MemoryBus,
+    source_identity: String,
+}
+
+impl Transport for MemoryTransport {
+    fn try_publication(&self, channel: &str) -> Result<Box<dyn Publication>, String> {
+        let params = channel_params(channel);
+        let mut state = self.bus.state.lock().unwrap();
+        if state
+            .taken_ports
+            .iter()
+            .any(|port| channel_key(channel) == format!("control:{}", port))
+        {
+            return Err(format!("Address already in use by channel {}", channel));
+        }
+        let response_correlation_id = params.get("response-correlation-id");
+        // Responses go to the subscription that the publication of the answered image asked for
+        let key = match (params.get("control-mode"), response_correlation_id) {
+            (Some(&"response"), Some(correlation_id)) => state
+                .publications
+                .iter()
+                .find(|publication| publication.correlation_id.to_string() == *correlation_id)
+                .and_then(|publication| publication.response_key.clone())
+                .unwrap_or_default(),
+            _ => channel_key(channel),
+        };
+        let response_key = match params.get("control-mode") {
+            Some(&"response") => None,
+            _ => response_correlation_id.map(|id| format!("response:{}", id)),
+        };
+        state.next_session_id += 1;
+        state.next_correlation_id += 1;
+        let publication = PublicationEntry {
+            key: key.clone(),
+            session_id: state.next_session_id,
+            source_identity: self.source_identity.clone(),
+            correlation_id: state.next_correlation_id,
+            response_key,
+        };
+        let session_id = publication.session_id;
+        state
+            .subscriptions
+            .iter()
+            .filter_map(|subscription| {
+                let subscription = subscription.lock().unwrap();
+                (subscription.key == key).then_some(subscription)
+            })
+            .for_each(|mut subscription| subscription.add_image(&publication));
+        state.publications.push(publication);
+        Ok(Box::new(MemoryPublication {
+            bus: self.bus.clone(),
+            key,
+            session_id,
+        }))
+    }
+
+    fn try_subscription(&self, channel: &str) -> Result<Box<dyn Subscription>, String> {
+        let params = channel_params(channel);
+        let mut state = self.bus.state.lock().unwrap();
+        if state
+            .taken_ports
+            .iter()
+            .any(|port| channel_key(channel) == format!("endpoint:{}", port))
+        {
+            return Err(format!("Address already in use by channel {}", channel));
+        }
+        state.next_correlation_id += 1;
+        let registration_id = state.next_correlation_id;
+        let key = match params.get("control-mode") {
+            Some(&"response") => format!("response:{}", registration_id),
+            _ => channel_key(channel),
+        };
+        let mut subscription = SubscriptionState {
+            key: key.clone(),
+            registration_id,
+            session_id: params
+                .get("session-id")
+                .map(|session_id| session_id.parse().unwrap()),
+            images: HashMap::new(),
+            fragments: VecDeque::new(),
+            events: vec![],
+        };
+        state
+            .publications
+            .iter()
+            .filter(|publication| publication.key == key)
+            .for_each(|publication| subscription.add_image(publication));
+        let subscription = Arc::new(Mutex::new(subscription));
+        state.subscriptions.push(subscription.clone());
+        Ok(Box::new(MemorySubscription {
+            bus: self.bus.clone(),
+            state: subscription,
+        }))
+    }
+}
+
+struct MemoryPublication {
+    bus: MemoryBus,
+    key: String,
+    session_id: i32,
+}
+
+impl Publication for MemoryPublication {
+    fn publish(&self, buffer: &[u8]) {
+        let state = self.bus.state.lock().unwrap();
+        state.subscriptions.iter().for_each(|subscription| {
+            let mut subscription = subscription.lock().unwrap();
+            if subscription.key == self.key && subscription.images.contains_key(&self.session_id) {
+                subscription
+                    .fragments
+                    .push_back((self.session_id, buffer.to_vec()));
+            }
+        });
+    }
+
+    fn session_id(&self) -> i32 {
+        self.session_id
+    }
+
+    fn stream_id(&self) -> i32 {
+        STREAM_ID
+    }
+
+    fn is_connected(&self) -> bool {
+        let state = self.bus.state.lock().unwrap();
+        state.subscriptions.iter().any(|subscription| {
+            let subscription = subscription.lock().unwrap();
+            subscription.key == self.key && subscription.images.contains_key(&self.session_id)
+        })
+    }
+
+    fn close(&self) {
+        let mut state = self.bus.state.lock().unwrap();
+        state
+            .publications
+            .retain(|publication| publication.session_id != self.session_id);
+        state.subscriptions.iter().for_each(|subscription| {
+            subscription.lock().unwrap().remove_image(self.session_id);
+        });
+    }
+}
+
+struct MemorySubscription {
+    bus: MemoryBus,
+    state: Arc<Mutex<SubscriptionState>>,
+}
+
+impl Subscription for MemorySubscription {
+    fn poll(&self, on_fragment: &mut dyn FnMut(&[u8], &FragmentHeader)) -> usize {
+        // Handlers may publish on the bus, so fragments are taken out before delivering them
+        let fragments: Vec<(i32, Vec<u8>)> =
+            self.state.lock().unwrap().fragments.drain(..).collect();
+        fragments.iter().for_each(|(session_id, buffer)| {
+            on_fragment(
+                buffer,
+                &FragmentHeader {
+                    session_id: *session_id,
+                    stream_id: STREAM_ID,
+                },
+            )
+        });
+        fragments.len()
+    }
+
+    fn has_image(&self, session_id: i32) -> bool {
+        self.state.lock().unwrap().images.contains_key(&session_id)
+    }
+
+    fn image_events(&self) -> Vec<ImageEvent> {
+        self.state.lock().unwrap().events.drain(..).collect()
+    }
+
+    fn registration_id(&self) -> i64 {
+        self.state.lock().unwrap().registration_id
+    }
+
+    fn close(&self) {
+        self.bus
+            .state
+            .lock()
+            .unwrap()
+            .subscriptions
+            .retain(|subscription| !Arc::ptr_eq(subscription, &self.state));
+    }
+}
+
+/// Publications and subscriptions match when they share the port of their control address,
+/// for multi-destination-cast channels, or of their endpoint address otherwise.
+fn channel_key(channel: &str) -> String {
+    let params = channel_params(channel);
+    if channel.starts_with("aeron:ipc") {
+        return format!("ipc:{}", params.get("stream-id").unwrap_or(&""));
+    }
+    let port = |address: &str| address.rsplit(':').next().unwrap_or_default().to_owned();
+    match (
+        params.get("control-mode"),
+        params.get("control"),
+        params.get("endpoint"),
+    ) {
+        (Some(&"dynamic"), Some(control), _) => format!("control:{}", port(control)),
+        (_, _, Some(endpoint)) => format!("endpoint:{}", port(endpoint)),
+        _ => channel.to_owned(),
+    }
+}
+
+fn channel_params(channel: &str) -> HashMap<&str, &str> {
+    let (_, params) = channel.split_once('?').unwrap_or((channel, ""));
+    params
+        .split('|')
+        .filter_map(|param| param.split_once('='))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_key() {
+        assert_eq!(
+            channel_key("aeron:udp?endpoint=10.0.0.1:40124|interface=192.168.1.0/24"),
+            channel_key("aeron:udp?endpoint=0.0.0.0:40124")
+        );
+        assert_eq!(
+            channel_key("aeron:udp?control=10.0.0.1:32105|control-mode=dynamic"),
+            channel_key("aeron:udp?endpoint=0.0.0.0:0|control=10.0.0.1:32105|control-mode=dynamic")
+        );
+        assert_ne!(
+            channel_key("aeron:ipc?stream-id=40124"),
+            channel_key("aeron:ipc?stream-id=32105")
+        );
+    }
+
+    #[test]
+    fn test_publish_and_poll() {
+        let bus = MemoryBus::new();
+        let subscription = bus
+            .transport("10.0.0.2:5000")
+            .subscription("aeron:udp?endpoint=0.0.0.0:40123");
+        let publication = bus
+            .transport("10.0.0.1:5000")
+            .publication("aeron:udp?endpoint=10.0.0.2:40123");
+        assert_eq!(
+            subscription.image_events(),
+            vec![ImageEvent::Available {
+                session_id: publication.session_id(),
+                source_identity: String::from("10.0.0.1:5000"),
+                correlation_id: 2,
+            }]
+        );
+
+        publication.publish(b"hello");
+        let mut received = vec![];
+        let count = subscription
+            .poll(&mut |buffer, header| received.push((buffer.to_vec(), header.session_id)));
+        assert_eq!(count, 1);
+        assert_eq!(
+            received,
+            vec![(b"hello".to_vec(), publication.session_id())]
+        );
+
+        publication.close();
+        assert!(!subscription.has_image(publication.session_id()));
+        assert_eq!(
+            subscription.image_events(),
+            vec![ImageEvent::Unavailable {
+                session_id: publication.session_id()
+            }]
+        );
+    }
+}
@@ -0,0 +1,66 @@
+#[cfg(test)]
+pub mod memory;
+
+/// Details of the publication a received fragment comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub session_id: i32,
+    pub stream_id: i32,
+}
+
+/// Notifications about the publications connecting to, or disconnecting from, a subscription
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageEvent {
+    Available {
+        session_id: i32,
+        source_identity: String,
+        /// Identifies the image to the response publications sent back to its publisher
+        correlation_id: i64,
+    },
+    Unavailable {
+        session_id: i32,
+    },
+}
+
+pub trait Publication {
+    fn publish(&self, buffer: &[u8]);
+    fn session_id(&self) -> i32;
+    fn stream_id(&self) -> i32;
+    /// Whether a subscriber is receiving what is published
+    fn is_connected(&self) -> bool;
+    fn close(&self);
+}
+
+pub trait Subscription {
+    /// Delivers the fragments received so far to the handler, returning how many there were
+    fn poll(&self, on_fragment: &mut dyn FnMut(&[u8], &FragmentHeader)) -> usize;
+    fn has_image(&self, session_id: i32) -> bool;
+    /// Drains the image availability events occurred since the last call
+    fn image_events(&self) -> Vec<ImageEvent>;
+    /// Identifies a response subscription to the publications it sends requests on
+    fn registration_id(&self) -> i64;
+    fn close(&self);
+}
+
+/// Factory of the publications and subscriptions that carry the tunnel traffic, given their
+/// Aeron channel URI. Response channels, with `control-mode=response`, pair the publication
+/// answering an image with the subscription of its publisher, given their correlation ids.
+/// Channels are on the default stream unless they name another with a `stream-id` parameter,
+/// as IPC ones do, the only thing telling them apart.
+pub trait Transport {
+    /// Opens a publication, failing when its channel can't be, such as when its control port is
+    /// taken
+    fn try_publication(&self, channel: &str) -> Result<Box<dyn Publication>, String>;
+    /// Opens a subscription, failing when its channel can't be, such as when its port is taken
+    fn try_subscription(&self, channel: &str) -> Result<Box<dyn Subscription>, String>;
+
+    fn publication(&self, channel: &str) -> Box<dyn Publication> {
+        self.try_publication(channel)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn subscription(&self, channel: &str) -> Box<dyn Subscription> {
+        self.try_subscription(channel)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
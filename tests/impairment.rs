@@ -0,0 +1,169 @@
+use std::net::UdpSocket;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const PORT: usize = 41123;
+const CONTROL: usize = 33104;
+const PORT_OFFSET: usize = 1000;
+const DATAGRAMS: u32 = 200;
+const IMPAIRMENT: &str = "--loss 10 --duplicate 5 --reorder 10 --delay 5 --jitter 3";
+const RELAY_PORT: usize = 42123;
+const RELAY_CONTROL: usize = 34104;
+const RELAYED: u32 = 1000;
+
+/// Kills the spawned processes when the test ends, successfully or not
+struct Processes(Vec<Child>);
+
+impl Drop for Processes {
+    fn drop(&mut self) {
+        self.0.iter_mut().rev().for_each(|child| {
+            let _ = child.kill();
+            let _ = child.wait();
+        });
+    }
+}
+
+fn media_driver(dir: &str) -> Child {
+    Command::new("java")
+        .args([
+            "--add-opens=java.base/sun.nio.ch=ALL-UNNAMED",
+            "--add-opens=java.base/jdk.internal.misc=ALL-UNNAMED",
+            "--add-opens=java.base/java.util.zip=ALL-UNNAMED",
+            "-cp",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/src/bin/aeron-all-1.48.0.jar"),
+            "-Daeron.dir.delete.on.start=true",
+            &format!("-Daeron.dir={}", dir),
+            "io.aeron.driver.MediaDriver",
+        ])
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Error spawning Aeron driver process")
+}
+
+fn rudp_tunnel(args: &str) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_rudp-tunnel"))
+        .args(args.split_whitespace())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Error spawning rudp-tunnel")
+}
+
+#[test]
+fn test_impair_between_udp_sockets() {
+    let server = UdpSocket::bind(("127.0.0.1", RELAY_PORT as u16)).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let front = format!("127.0.0.1:{}", RELAY_PORT + PORT_OFFSET);
+    let _processes = Processes(vec![rudp_tunnel(&format!(
+        "impair -s 127.0.0.1 -p {} -c {} -x 1 --port-offset {} --loss 20 --duplicate 20 --reorder 20",
+        RELAY_PORT, RELAY_CONTROL, PORT_OFFSET
+    ))]);
+
+    // Probe until the proxy relays
+    let mut buffer = [0; 1500];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        assert!(Instant::now() < deadline, "Datagrams never relayed");
+        client.send_to(b"probe", &front).unwrap();
+        if server.recv(&mut buffer).is_ok() {
+            break;
+        }
+    }
+
+    // Sent meanwhile, not to overflow the receive buffer of the server socket
+    let sender = std::thread::spawn(move || {
+        for sequence in 0..RELAYED {
+            client.send_to(&sequence.to_be_bytes(), &front).unwrap();
+            std::thread::sleep(Duration::from_micros(100));
+        }
+    });
+    let mut received = vec![];
+    while let Ok(n) = server.recv(&mut buffer) {
+        if n == 4 {
+            received.push(u32::from_be_bytes(buffer[..4].try_into().unwrap()));
+        }
+    }
+    sender.join().unwrap();
+
+    let mut distinct = received.clone();
+    distinct.sort();
+    distinct.dedup();
+    let lost = RELAYED as usize - distinct.len();
+    let duplicated = received.len() - distinct.len();
+    let reordered = received.windows(2).filter(|pair| pair[1] < pair[0]).count();
+    // A fifth of the datagrams each time, give or take what chance allows over a thousand
+    assert!((100..=300).contains(&lost), "{} datagrams lost", lost);
+    assert!(
+        (80..=250).contains(&duplicated),
+        "{} datagrams duplicated",
+        duplicated
+    );
+    assert!(reordered >= 50, "{} datagrams reordered", reordered);
+}
+
+#[test]
+#[ignore = "requires java to run the Aeron media driver"]
+fn test_delivery_through_impaired_network() {
+    let service = UdpSocket::bind("127.0.0.1:0").unwrap();
+    service
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let application = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let endpoint = {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().to_string()
+    };
+    let service_addr = service.local_addr().unwrap();
+
+    let mut processes = Processes(vec![media_driver(&format!(
+        "/tmp/aeron-{}",
+        whoami::username()
+    ))]);
+    std::thread::sleep(Duration::from_secs(2));
+    processes.0.push(rudp_tunnel(&format!(
+        "-u 127.0.0.1 -e {} -l -p {} -c {} -x 1 --port-offset {} -d -n",
+        service_addr, PORT, CONTROL, PORT_OFFSET
+    )));
+    std::thread::sleep(Duration::from_secs(2));
+    processes.0.push(rudp_tunnel(&format!(
+        "impair -s 127.0.0.1 -p {} -c {} -x 1 --port-offset {} {}",
+        PORT, CONTROL, PORT_OFFSET, IMPAIRMENT
+    )));
+    processes.0.push(rudp_tunnel(&format!(
+        "-s 127.0.0.1 -e {} -p {} -c {} -d -n",
+        endpoint,
+        PORT + PORT_OFFSET,
+        CONTROL + PORT_OFFSET
+    )));
+
+    // Probe until the client has completed the handshake and the tunnel delivers
+    let mut buffer = [0; 1500];
+    let deadline = Instant::now() + Duration::from_secs(60);
+    loop {
+        assert!(Instant::now() < deadline, "Tunnel never established");
+        application.send_to(b"probe", &endpoint).unwrap();
+        if service.recv(&mut buffer).is_ok() {
+            break;
+        }
+    }
+
+    for sequence in 0..DATAGRAMS {
+        application
+            .send_to(&sequence.to_be_bytes(), &endpoint)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+    }
+
+    let mut received = vec![];
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while received.len() < DATAGRAMS as usize && Instant::now() < deadline {
+        if let Ok(n) = service.recv(&mut buffer) {
+            if n == 4 {
+                received.push(u32::from_be_bytes(buffer[..4].try_into().unwrap()));
+            }
+        }
+    }
+    assert_eq!(received, (0..DATAGRAMS).collect::<Vec<u32>>());
+}